@@ -0,0 +1,79 @@
+//! Annotating the pods protected by a PDB with their server's player count,
+//! for `kubectl` and other controllers to read.
+
+use crate::config::{Config, ServerConfig};
+use anyhow::{Context, Result, anyhow};
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::api::policy::v1::PodDisruptionBudget;
+use kube::Client;
+use kube::api::{Api, ListParams, Patch, PatchParams};
+use kube::core::Selector;
+
+/// Set the annotation on every pod selected by the PDB to the number of
+/// online players, skipping those that already have it.
+pub async fn annotate_pods(
+    client: &Client,
+    config: &Config,
+    key: &str,
+    server: &ServerConfig,
+    players_online: u32,
+) -> Result<()> {
+    let pdb_name = &server.key();
+    let api: Api<Pod> = Api::namespaced(client.clone(), server.namespace(config));
+    let pods = selected_pods(client, config, server).await?;
+    let value = players_online.to_string();
+    for pod in pods {
+        let Some(pod_name) = pod.metadata.name.as_deref() else {
+            continue;
+        };
+        let current = pod.metadata.annotations.as_ref().and_then(|a| a.get(key));
+        if current == Some(&value) {
+            continue;
+        }
+        if config.dry_run {
+            tracing::info!(
+                pdb_name,
+                "Dry run: would annotate pod {pod_name} with {key}={value}."
+            );
+            continue;
+        }
+        let patch = serde_json::json!({
+            "metadata": { "annotations": { key: value } }
+        });
+        let params = PatchParams {
+            field_manager: Some(config.field_manager.clone()),
+            ..Default::default()
+        };
+        api.patch(pod_name, &params, &Patch::Merge(&patch))
+            .await
+            .with_context(|| format!("Failed to annotate pod {pod_name}"))?;
+        tracing::debug!(pdb_name, "Annotated pod {pod_name} with {key}={value}.");
+    }
+    Ok(())
+}
+
+/// The pods selected by the server's PDB.
+pub async fn selected_pods(
+    client: &Client,
+    config: &Config,
+    server: &ServerConfig,
+) -> Result<Vec<Pod>> {
+    let namespace = server.namespace(config);
+    let pdbs: Api<PodDisruptionBudget> = Api::namespaced(client.clone(), namespace);
+    let pdb = pdbs
+        .get(&server.pdb_name)
+        .await
+        .context("Failed to get PodDisruptionBudget")?;
+    let selector = pdb
+        .spec
+        .and_then(|s| s.selector)
+        .ok_or_else(|| anyhow!("PodDisruptionBudget has no selector"))?;
+    let selector = Selector::try_from(selector)?;
+
+    let api: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let pods = api
+        .list(&ListParams::default().labels_from(&selector))
+        .await
+        .context("Failed to list pods")?;
+    Ok(pods.items)
+}
@@ -0,0 +1,68 @@
+//! A minimal client for the RakNet unconnected ping used by Bedrock Edition
+//! servers to advertise themselves.
+
+use anyhow::{Result, anyhow};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const UNCONNECTED_PING: u8 = 0x01;
+const UNCONNECTED_PONG: u8 = 0x1c;
+/// The "offline message" magic that every unconnected RakNet packet carries.
+const MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+
+/// Ping a Bedrock server and return its `(online, max)` player counts.
+pub async fn ping(host: &str, port: u16, timeout: Duration) -> Result<(u32, u32)> {
+    tokio::time::timeout(timeout, ping_inner(host, port))
+        .await
+        .map_err(|_| anyhow!("Bedrock ping timed out"))?
+}
+
+async fn ping_inner(host: &str, port: u16) -> Result<(u32, u32)> {
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("{host} did not resolve to any address"))?;
+    let bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+
+    // Packet ID, client time, magic, and client GUID.
+    let mut packet = vec![UNCONNECTED_PING];
+    packet.extend_from_slice(&0i64.to_be_bytes());
+    packet.extend_from_slice(&MAGIC);
+    packet.extend_from_slice(&rand::random::<u64>().to_be_bytes());
+    socket.send(&packet).await?;
+
+    let mut buf = [0u8; 2048];
+    let len = socket.recv(&mut buf).await?;
+    parse_pong(&buf[..len])
+}
+
+/// Parse an unconnected pong, whose payload is a semicolon-delimited string
+/// like `MCPE;<motd>;<protocol>;<version>;<online>;<max>;...`.
+fn parse_pong(packet: &[u8]) -> Result<(u32, u32)> {
+    let invalid = || anyhow!("Invalid Bedrock pong response");
+    // Packet ID, server time, server GUID, magic, then the string length.
+    let header = 1 + 8 + 8 + MAGIC.len();
+    if packet.first() != Some(&UNCONNECTED_PONG) || packet.len() < header + 2 {
+        return Err(invalid());
+    }
+    let len = usize::from(u16::from_be_bytes([packet[header], packet[header + 1]]));
+    let motd = packet
+        .get(header + 2..header + 2 + len)
+        .ok_or_else(invalid)?;
+    let motd = String::from_utf8_lossy(motd);
+
+    let fields: Vec<&str> = motd.split(';').collect();
+    match (fields.get(4), fields.get(5)) {
+        (Some(online), Some(max)) => Ok((online.parse()?, max.parse()?)),
+        _ => Err(invalid()),
+    }
+}
@@ -0,0 +1,143 @@
+//! An HTTP API for controlling a running controller from tooling that can't
+//! easily send it signals: forcing an update, reading its state, and toggling
+//! dry run.
+
+use crate::config::{Config, ControlApi};
+use crate::web::Status;
+use anyhow::Result;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+/// The largest request body that is read.
+const MAX_BODY_BYTES: usize = 1024;
+
+/// What the control API acts on.
+#[derive(Clone)]
+pub struct Handles {
+    pub config: Arc<RwLock<Config>>,
+    pub force_update: Arc<Notify>,
+    pub status: Arc<Status>,
+}
+
+/// Serve the `/update`, `/state`, and `/dry-run` endpoints until the process
+/// exits.
+pub async fn serve(api: ControlApi, handles: Handles) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", api.port)).await?;
+    match &api.token {
+        Some(_) => tracing::info!("Serving the control API on port {}.", api.port),
+        None => tracing::warn!(
+            "Serving the control API on port {} without CONTROL_API_TOKEN.",
+            api.port
+        ),
+    }
+
+    let token = Arc::new(api.token.map(|t| t.0));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (handles, token) = (handles.clone(), token.clone());
+        tokio::spawn(async move {
+            let service = service_fn(|req| handle(req, handles.clone(), token.clone()));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Control API connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    handles: Handles,
+    token: Arc<Option<String>>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if let Some(token) = token.as_deref() {
+        let authorized = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| constant_time_eq(v.as_bytes(), token.as_bytes()));
+        if !authorized {
+            return Ok(text(StatusCode::UNAUTHORIZED, "unauthorized"));
+        }
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/update") => {
+            tracing::info!("Update requested through the control API.");
+            handles.force_update.notify_one();
+            Ok(text(StatusCode::ACCEPTED, "update requested"))
+        }
+        (&Method::GET, "/state") => {
+            let mut state = handles.status.to_json();
+            state["dry_run"] = handles.config.read().unwrap().dry_run.into();
+            Ok(json(StatusCode::OK, &state))
+        }
+        (&Method::POST, "/dry-run") => {
+            let body = match Limited::new(req.into_body(), MAX_BODY_BYTES)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return Ok(text(StatusCode::BAD_REQUEST, "invalid body")),
+            };
+            let enabled = serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|v| v.get("enabled")?.as_bool());
+            let Some(enabled) = enabled else {
+                return Ok(text(
+                    StatusCode::BAD_REQUEST,
+                    "expected {\"enabled\": true|false}",
+                ));
+            };
+            // Like SIGUSR2, this applies from the next update on.
+            let mut config = handles.config.write().unwrap();
+            if config.dry_run != enabled {
+                config.dry_run = enabled;
+                if enabled {
+                    tracing::warn!("Dry run enabled through the control API.");
+                } else {
+                    tracing::warn!("Dry run disabled through the control API.");
+                }
+            }
+            Ok(json(
+                StatusCode::OK,
+                &serde_json::json!({ "dry_run": config.dry_run }),
+            ))
+        }
+        (_, "/update" | "/state" | "/dry-run") => {
+            Ok(text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"))
+        }
+        _ => Ok(text(StatusCode::NOT_FOUND, "not found")),
+    }
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+fn json(status: StatusCode, body: &serde_json::Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+/// Compare two byte strings without leaking where they differ through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,101 @@
+//! Discovery of PDBs by label, with the server each one protects read from
+//! its annotations. PDBs are looked for in the pod's own namespace, or in
+//! those of `WATCH_NAMESPACES`.
+
+use crate::config::{Config, ServerConfig, WatchNamespaces, server_key, split_host_port};
+use anyhow::{Context, Result, anyhow, bail};
+use k8s_openapi::api::policy::v1::PodDisruptionBudget;
+use kube::Client;
+use kube::api::{Api, ListParams};
+
+const SERVER_HOST_ANNOTATION: &str = "mc-pdb-mgr/server-host";
+const SERVER_PORT_ANNOTATION: &str = "mc-pdb-mgr/server-port";
+const QUERY_PORT_ANNOTATION: &str = "mc-pdb-mgr/query-port";
+
+/// List the PDBs matching the configured label selector, skipping any that are
+/// already configured explicitly or whose annotations are invalid.
+pub async fn discover(client: &Client, config: &Config) -> Result<Vec<ServerConfig>> {
+    let Some(selector) = &config.pdb_label_selector else {
+        return Ok(Vec::new());
+    };
+    let params = ListParams::default().labels(selector);
+    let apis: Vec<Api<PodDisruptionBudget>> = match &config.watch_namespaces {
+        None => vec![Api::namespaced(client.clone(), &config.pod_namespace)],
+        Some(WatchNamespaces::All) => vec![Api::all(client.clone())],
+        Some(WatchNamespaces::Only(namespaces)) => namespaces
+            .iter()
+            .map(|namespace| Api::namespaced(client.clone(), namespace))
+            .collect(),
+    };
+    let mut pdbs = Vec::new();
+    for api in apis {
+        let list = api
+            .list(&params)
+            .await
+            .context("Failed to list PodDisruptionBudgets")?;
+        pdbs.extend(list);
+    }
+
+    let mut servers = Vec::new();
+    for pdb in pdbs {
+        let Some(name) = pdb.metadata.name.as_deref() else {
+            continue;
+        };
+        // PDBs in the pod's own namespace are named as if configured directly.
+        let namespace = pdb
+            .metadata
+            .namespace
+            .clone()
+            .filter(|namespace| *namespace != config.pod_namespace);
+        let pdb_name = server_key(namespace.as_deref(), name);
+        if config.servers.iter().any(|s| s.key() == pdb_name) {
+            continue;
+        }
+        match server_from_annotations(&pdb, config) {
+            Ok(server) => servers.push(ServerConfig {
+                namespace,
+                ..server
+            }),
+            Err(e) => tracing::warn!(pdb_name, "Skipping discovered PDB: {e}"),
+        }
+    }
+    Ok(servers)
+}
+
+fn server_from_annotations(pdb: &PodDisruptionBudget, config: &Config) -> Result<ServerConfig> {
+    let annotation = |name: &str| {
+        pdb.metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(name))
+            .map(String::as_str)
+    };
+    let server_host = annotation(SERVER_HOST_ANNOTATION)
+        .with_context(|| format!("missing {SERVER_HOST_ANNOTATION} annotation"))?;
+    // The host may include the port instead, e.g. [::1]:25565.
+    let (server_host, host_port) = split_host_port(server_host)
+        .map_err(|e| anyhow!("{SERVER_HOST_ANNOTATION} annotation {e}"))?;
+    let server_port: u16 = match (annotation(SERVER_PORT_ANNOTATION), host_port) {
+        (Some(port), _) => port
+            .parse()
+            .with_context(|| format!("invalid {SERVER_PORT_ANNOTATION} annotation"))?,
+        (None, Some(port)) => port,
+        (None, None) => bail!("missing {SERVER_PORT_ANNOTATION} annotation"),
+    };
+    let query_port: u16 = match annotation(QUERY_PORT_ANNOTATION) {
+        Some(port) => port
+            .parse()
+            .with_context(|| format!("invalid {QUERY_PORT_ANNOTATION} annotation"))?,
+        None => server_port,
+    };
+    Ok(ServerConfig {
+        pdb_name: pdb.metadata.name.clone().unwrap_or_default(),
+        server_host,
+        server_port,
+        query_port,
+        threshold: config.threshold.clone(),
+        fallback_ports: Vec::new(),
+        aggregate_hosts: Vec::new(),
+        namespace: None,
+    })
+}
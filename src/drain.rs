@@ -0,0 +1,78 @@
+//! Noticing when the node of a server's pods is cordoned, as it is right
+//! before a drain evicts them.
+
+use crate::annotate;
+use crate::config::{Config, ServerConfig};
+use anyhow::Result;
+use futures::stream::StreamExt;
+use k8s_openapi::api::core::v1::Node;
+use kube::Client;
+use kube::api::Api;
+use kube::runtime::{WatchStreamExt, watcher};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use tokio_util::sync::CancellationToken;
+
+/// The names of the nodes that are cordoned.
+#[derive(Default)]
+pub struct Cordoned(RwLock<HashSet<String>>);
+
+impl Cordoned {
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().is_empty()
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.0.read().unwrap().contains(node)
+    }
+}
+
+/// Watch the nodes, keeping track of which are cordoned, until shutdown.
+pub async fn watch(client: Client, cordoned: Arc<Cordoned>, shutdown: CancellationToken) {
+    let api: Api<Node> = Api::all(client);
+    let stream = watcher(api, watcher::Config::default())
+        .default_backoff()
+        .applied_objects();
+    let mut stream = std::pin::pin!(stream);
+    loop {
+        let node = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            next = stream.next() => match next {
+                Some(Ok(node)) => node,
+                Some(Err(e)) => {
+                    tracing::warn!("Failed to watch Nodes: {e}");
+                    continue;
+                }
+                None => return,
+            },
+        };
+        let Some(name) = node.metadata.name else {
+            continue;
+        };
+        let unschedulable = node.spec.and_then(|s| s.unschedulable).unwrap_or(false);
+        let mut cordoned = cordoned.0.write().unwrap();
+        if unschedulable && cordoned.insert(name.clone()) {
+            tracing::info!("Node {name} was cordoned.");
+        } else if !unschedulable && cordoned.remove(&name) {
+            tracing::info!("Node {name} was uncordoned.");
+        }
+    }
+}
+
+/// The cordoned node of one of the pods selected by the server's PDB, if any.
+pub async fn cordoned_node(
+    client: &Client,
+    config: &Config,
+    server: &ServerConfig,
+    cordoned: &Cordoned,
+) -> Result<Option<String>> {
+    // Most of the time no node is cordoned, so there is nothing to look up.
+    if cordoned.is_empty() {
+        return Ok(None);
+    }
+    let pods = annotate::selected_pods(client, config, server).await?;
+    Ok(pods
+        .into_iter()
+        .filter_map(|pod| pod.spec?.node_name)
+        .find(|node| cordoned.contains(node)))
+}
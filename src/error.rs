@@ -0,0 +1,99 @@
+use thiserror::Error;
+
+/// Any error from a lower-level library, such as a failed status query.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The ways updating a PDB can fail, so that callers can tell transient
+/// failures from permanent ones.
+#[derive(Debug, Error)]
+pub enum MgrError {
+    /// One or more configuration values are missing or invalid.
+    #[error("Invalid configuration:\n  - {}", .0.join("\n  - "))]
+    ConfigInvalid(Vec<String>),
+    /// A server could not be queried for its players.
+    #[error("Failed to get server player count for {host}: {source}")]
+    QueryFailed { host: String, source: BoxError },
+    /// A server accepts connections but doesn't answer status queries yet,
+    /// as while it is still starting up.
+    #[error("Server {host} isn't ready to be queried yet: {reason}")]
+    ServerStarting { host: String, reason: String },
+    /// A server's response can't be used to decide the state of its PDB.
+    #[error("Server {host} reported a max of 0 players, so {percent:.0}% of it can't be used.")]
+    ZeroMaxPlayers { host: String, percent: f64 },
+    /// The PDB to update doesn't exist.
+    #[error("PodDisruptionBudget {pdb_name} does not exist.")]
+    PdbNotFound { pdb_name: String },
+    /// The PDB could not be read.
+    #[error("Failed to get PodDisruptionBudget {pdb_name}: {source}")]
+    GetFailed {
+        pdb_name: String,
+        source: Box<kube::Error>,
+    },
+    /// The PDB could not be patched.
+    #[error("Failed to patch PodDisruptionBudget {pdb_name}: {source}")]
+    PatchFailed {
+        pdb_name: String,
+        source: Box<kube::Error>,
+    },
+    /// The patch conflicted with another change to the object, and may
+    /// succeed if retried.
+    #[error("Patch of {name} conflicted with another change: {source}")]
+    PatchConflict {
+        name: String,
+        source: Box<kube::Error>,
+    },
+    /// The PDB could not be created.
+    #[error("Failed to create PodDisruptionBudget {pdb_name}: {source}")]
+    CreateFailed {
+        pdb_name: String,
+        source: Box<kube::Error>,
+    },
+    /// The custom resource to update doesn't exist.
+    #[error("{kind} {name} does not exist.")]
+    TargetNotFound { kind: String, name: String },
+    /// The custom resource could not be read.
+    #[error("Failed to get {kind} {name}: {source}")]
+    TargetGetFailed {
+        kind: String,
+        name: String,
+        source: Box<kube::Error>,
+    },
+    /// The custom resource could not be patched.
+    #[error("Failed to patch {kind} {name}: {source}")]
+    TargetPatchFailed {
+        kind: String,
+        name: String,
+        source: Box<kube::Error>,
+    },
+    /// No pods match the selector of the annotated pods.
+    #[error("No pods match {selector}.")]
+    NoPodsSelected { selector: String },
+}
+
+impl MgrError {
+    /// Whether the PDB, custom resource, or pods don't exist, which retrying
+    /// won't change.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            MgrError::PdbNotFound { .. }
+                | MgrError::TargetNotFound { .. }
+                | MgrError::NoPodsSelected { .. }
+        )
+    }
+
+    /// Whether the cluster rejected the client's credentials, as when its
+    /// service account token was rotated.
+    pub fn is_unauthorized(&self) -> bool {
+        let source = match self {
+            MgrError::GetFailed { source, .. }
+            | MgrError::PatchFailed { source, .. }
+            | MgrError::PatchConflict { source, .. }
+            | MgrError::CreateFailed { source, .. }
+            | MgrError::TargetGetFailed { source, .. }
+            | MgrError::TargetPatchFailed { source, .. } => source,
+            _ => return false,
+        };
+        matches!(source.as_ref(), kube::Error::Api(s) if s.code == 401 || s.is_forbidden())
+    }
+}
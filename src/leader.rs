@@ -0,0 +1,188 @@
+//! Leader election using a `coordination.k8s.io/v1` Lease, so that only one
+//! replica updates the PDBs at a time.
+
+use crate::config::LeaderElection;
+use anyhow::{Result, anyhow};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::jiff::{SignedDuration, Timestamp};
+use kube::Client;
+use kube::api::{Api, PostParams};
+use std::time::Instant;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// Competes for the Lease and tracks whether this instance holds it.
+pub struct LeaderElector {
+    api: Api<Lease>,
+    config: LeaderElection,
+    leader: watch::Sender<bool>,
+}
+
+impl LeaderElector {
+    pub fn new(client: Client, namespace: &str, config: LeaderElection) -> Self {
+        LeaderElector {
+            api: Api::namespaced(client, namespace),
+            config,
+            leader: watch::Sender::new(false),
+        }
+    }
+
+    /// Whether this instance currently holds the Lease.
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    /// Watch for changes in leadership.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.leader.subscribe()
+    }
+
+    /// Acquire or renew the Lease every third of its duration until `shutdown`
+    /// is cancelled.
+    pub async fn run(&self, shutdown: CancellationToken) {
+        let name = &self.config.lease_name;
+        let lease_duration = self.config.lease_duration;
+        let mut last_renewed: Option<Instant> = None;
+        loop {
+            // A renewal must finish before the Lease could expire, since
+            // another replica may take it then. The Lease is renewed as of
+            // some time after `started`, so it lasts at least as long as
+            // assumed here.
+            let started = Instant::now();
+            let deadline = last_renewed.map_or(lease_duration, |t| {
+                lease_duration.saturating_sub(t.elapsed())
+            });
+            let result = tokio::time::timeout(deadline, self.try_acquire_or_renew())
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("timed out after {deadline:?}")));
+            let is_leader = match result {
+                Ok(true) => {
+                    last_renewed = Some(started);
+                    true
+                }
+                Ok(false) => {
+                    last_renewed = None;
+                    false
+                }
+                Err(e) => {
+                    tracing::warn!(lease_name = name, "Failed to renew lease: {e}");
+                    // Keep leading until another replica could consider the
+                    // Lease expired.
+                    last_renewed.is_some_and(|t| t.elapsed() < lease_duration)
+                }
+            };
+            let was_leader = self.leader.send_replace(is_leader);
+            if is_leader && !was_leader {
+                tracing::info!(lease_name = name, "Acquired lease, starting updates.");
+            } else if !is_leader && was_leader {
+                tracing::warn!(lease_name = name, "Lost lease, stopping updates.");
+            }
+
+            // Failed renewals are retried until the Lease runs out, and then
+            // leadership is given up in time.
+            let left = last_renewed
+                .filter(|_| is_leader)
+                .map_or(lease_duration, |t| {
+                    lease_duration.saturating_sub(t.elapsed())
+                });
+            tokio::select! {
+                _ = tokio::time::sleep((lease_duration / 3).min(left)) => {}
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+
+    /// Take the Lease if it is free or expired, or renew it if this instance
+    /// already holds it. Returns whether this instance now holds it.
+    async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let name = &self.config.lease_name;
+        let now = Timestamp::now();
+        let Some(mut lease) = self.api.get_opt(name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    ..Default::default()
+                },
+                spec: Some(self.spec(now, now, 0)),
+            };
+            return match self.api.create(&PostParams::default(), &lease).await {
+                Ok(_) => Ok(true),
+                // Another replica created it first.
+                Err(kube::Error::Api(s)) if s.is_already_exists() => Ok(false),
+                Err(e) => Err(e.into()),
+            };
+        };
+
+        let spec = lease.spec.take().unwrap_or_default();
+        let holder = spec.holder_identity.as_deref().filter(|h| !h.is_empty());
+        let held = holder == Some(self.config.identity.as_str());
+        if holder.is_some() && !held && !is_expired(&spec, now) {
+            return Ok(false);
+        }
+
+        let transitions = spec.lease_transitions.unwrap_or_default();
+        lease.spec = Some(if held {
+            let acquired = spec.acquire_time.map_or(now, |t| t.0);
+            self.spec(acquired, now, transitions)
+        } else {
+            self.spec(now, now, transitions + 1)
+        });
+        // The replace is conditional on the resourceVersion that was read, so
+        // if another replica changed the Lease in the meantime it wins.
+        match self.api.replace(name, &PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(s)) if s.is_conflict() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Give up the Lease if this instance holds it, so that another replica
+    /// can take over without waiting for it to expire.
+    pub async fn release(&self) -> Result<()> {
+        let name = &self.config.lease_name;
+        let Some(mut lease) = self.api.get_opt(name).await? else {
+            return Ok(());
+        };
+        let Some(spec) = lease
+            .spec
+            .as_mut()
+            .filter(|s| s.holder_identity.as_ref() == Some(&self.config.identity))
+        else {
+            return Ok(());
+        };
+        spec.holder_identity = None;
+        spec.lease_duration_seconds = Some(1);
+        spec.renew_time = Some(MicroTime(Timestamp::now()));
+        self.api
+            .replace(name, &PostParams::default(), &lease)
+            .await?;
+        self.leader.send_replace(false);
+        tracing::info!(lease_name = name, "Released lease.");
+        Ok(())
+    }
+
+    fn spec(&self, acquired: Timestamp, renewed: Timestamp, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.config.identity.clone()),
+            lease_duration_seconds: Some(
+                i32::try_from(self.config.lease_duration.as_secs()).unwrap_or(i32::MAX),
+            ),
+            acquire_time: Some(MicroTime(acquired)),
+            renew_time: Some(MicroTime(renewed)),
+            lease_transitions: Some(transitions),
+            ..Default::default()
+        }
+    }
+}
+
+/// Whether the holder of a Lease has failed to renew it in time.
+fn is_expired(spec: &LeaseSpec, now: Timestamp) -> bool {
+    let (Some(renewed), Some(secs)) = (&spec.renew_time, spec.lease_duration_seconds) else {
+        return true;
+    };
+    renewed
+        .0
+        .checked_add(SignedDuration::from_secs(secs.into()))
+        .is_ok_and(|expiry| expiry < now)
+}
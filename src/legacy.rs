@@ -0,0 +1,82 @@
+//! A minimal client for the legacy Server List Ping answered by Java servers
+//! from before 1.7, which predates the JSON status.
+
+use crate::proxy::{self, QueryProxy};
+use anyhow::{Result, anyhow};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const SERVER_LIST_PING: u8 = 0xfe;
+const PLUGIN_MESSAGE: u8 = 0xfa;
+const KICK: u8 = 0xff;
+/// The protocol version of 1.6.4, the last release to use this ping.
+const PROTOCOL_VERSION: u8 = 78;
+
+/// Ping a pre-1.7 Java server and return its `(online, max)` player counts.
+pub async fn ping(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    proxy: Option<&QueryProxy>,
+) -> Result<(u32, u32)> {
+    tokio::time::timeout(timeout, ping_inner(host, port, proxy))
+        .await
+        .map_err(|_| anyhow!("Legacy ping timed out"))?
+}
+
+async fn ping_inner(host: &str, port: u16, proxy: Option<&QueryProxy>) -> Result<(u32, u32)> {
+    let mut stream = proxy::connect(proxy, host, port).await?;
+    stream.write_all(&request(host, port)).await?;
+
+    // The response is a kick packet, whose reason is a UTF-16 string prefixed
+    // by its length in characters.
+    let mut header = [0u8; 3];
+    stream.read_exact(&mut header).await?;
+    if header[0] != KICK {
+        return Err(anyhow!("Invalid legacy ping response"));
+    }
+    let len = usize::from(u16::from_be_bytes([header[1], header[2]]));
+    let mut reason = vec![0u8; len * 2];
+    stream.read_exact(&mut reason).await?;
+    parse_reason(&reason)
+}
+
+/// Build the ping sent by 1.6 clients. Servers from 1.4 and 1.5 read only the
+/// first two bytes, and ignore the plugin message that follows.
+fn request(host: &str, port: u16) -> Vec<u8> {
+    let mut packet = vec![SERVER_LIST_PING, 0x01, PLUGIN_MESSAGE];
+    push_string(&mut packet, "MC|PingHost");
+    let host_len = host.encode_utf16().count();
+    // The protocol version, host, and port.
+    let data_len = 1 + 2 + host_len * 2 + 4;
+    packet.extend_from_slice(&u16::try_from(data_len).unwrap_or(u16::MAX).to_be_bytes());
+    packet.push(PROTOCOL_VERSION);
+    push_string(&mut packet, host);
+    packet.extend_from_slice(&i32::from(port).to_be_bytes());
+    packet
+}
+
+/// Append a string as its length in characters and its UTF-16 encoding.
+fn push_string(packet: &mut Vec<u8>, s: &str) {
+    let units: Vec<u16> = s.encode_utf16().collect();
+    packet.extend_from_slice(&u16::try_from(units.len()).unwrap_or(u16::MAX).to_be_bytes());
+    for unit in units {
+        packet.extend_from_slice(&unit.to_be_bytes());
+    }
+}
+
+/// Parse the kick reason, which is a NUL-delimited string like
+/// `§1\0<protocol>\0<version>\0<motd>\0<online>\0<max>`.
+fn parse_reason(reason: &[u8]) -> Result<(u32, u32)> {
+    let units: Vec<u16> = reason
+        .chunks_exact(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect();
+    let reason = String::from_utf16_lossy(&units);
+
+    let fields: Vec<&str> = reason.split('\0').collect();
+    match (fields.first(), fields.get(4), fields.get(5)) {
+        (Some(&"§1"), Some(online), Some(max)) => Ok((online.parse()?, max.parse()?)),
+        _ => Err(anyhow!("Invalid legacy ping response: {reason}")),
+    }
+}
@@ -0,0 +1,1781 @@
+//! The controller behind `minecraft-pdb-mgr`, which protects Minecraft
+//! servers with players online from voluntary disruption. The binary builds a
+//! [`Controller`] from the environment, but it can just as well be embedded in
+//! another operator.
+
+// `Config::to_json` lists every setting in a single `json!`.
+#![recursion_limit = "256"]
+
+mod annotate;
+mod bedrock;
+pub mod config;
+pub mod control;
+pub mod discovery;
+pub mod drain;
+pub mod error;
+pub mod leader;
+mod legacy;
+pub mod persist;
+mod ping;
+mod proxy;
+pub mod reconcile;
+pub mod rules;
+pub mod schedule;
+pub mod source;
+pub mod srv;
+pub mod state;
+pub mod stats;
+pub mod target;
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod watchdog;
+pub mod web;
+pub mod webhook;
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use config::{
+    Config, DEFAULT_MIN_PLAYERS, OverMaxPlayers, QueryFailureMode, RetryConfig, ServerConfig,
+    ShutdownState, Threshold, ZeroMaxPlayers,
+};
+use drain::Cordoned;
+use error::MgrError;
+use futures::stream::StreamExt;
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::{
+    Client,
+    config::{AuthInfo, KubeConfigOptions, Kubeconfig},
+    runtime::events::{Event, EventType, Recorder},
+};
+use leader::LeaderElector;
+use percentage::Percentage;
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use reconcile::Desired;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use source::{PlayerInfo, PlayerSource, Starting};
+use srv::SrvResolver;
+use state::{BreakerState, ServerState};
+use stats::Stats;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use target::ProtectionTarget;
+use tokio::sync::{Notify, watch};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use watchdog::Watchdog;
+use web::{Health, Status};
+
+/// Keeps the PDBs of the configured servers in line with their players.
+pub struct Controller {
+    config: Arc<RwLock<Config>>,
+    client: Client,
+    force_update: Arc<Notify>,
+}
+
+impl Controller {
+    /// Create a controller for `config`, using `client` to reach the cluster.
+    pub fn new(config: Config, client: Client) -> Self {
+        Controller {
+            config: Arc::new(RwLock::new(config)),
+            client,
+            force_update: Arc::new(Notify::new()),
+        }
+    }
+
+    /// The configuration, which may be replaced while running to apply it on
+    /// the next update.
+    pub fn config(&self) -> Arc<RwLock<Config>> {
+        self.config.clone()
+    }
+
+    /// Notified to update right away instead of waiting for the interval.
+    pub fn force_update(&self) -> Arc<Notify> {
+        self.force_update.clone()
+    }
+
+    /// Run until `shutdown` completes, then apply the shutdown state. In once
+    /// mode, a single update is made instead and its outcome returned.
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let token = CancellationToken::new();
+        // Every task watches the token, so the future only needs to cancel it.
+        let cancel = async {
+            shutdown.await;
+            token.cancel();
+            std::future::pending().await
+        };
+        tokio::select! {
+            result = self.serve(token.clone()) => result,
+            never = cancel => never,
+        }
+    }
+
+    async fn serve(self, shutdown: CancellationToken) -> Result<()> {
+        let Controller {
+            config,
+            client,
+            force_update,
+        } = self;
+        let initial = config.read().unwrap().clone();
+        tracing::info!("{}", initial.summary());
+
+        let ctx = Context::new(client.clone(), &initial, shutdown)?;
+        log_thresholds(&initial);
+
+        // Readiness fails if no update has succeeded in a few intervals.
+        let health = Arc::new(Health::new(health_max_age(&initial)));
+        if let Some(port) = initial.health_port {
+            let (health, status) = (health.clone(), ctx.status.clone());
+            tokio::spawn(async move {
+                if let Err(e) = web::serve(port, health, status).await {
+                    tracing::error!("Health probe server failed: {e}");
+                }
+            });
+        }
+        if let Some(api) = initial.control_api.clone() {
+            let handles = control::Handles {
+                config: config.clone(),
+                force_update: force_update.clone(),
+                status: ctx.status.clone(),
+            };
+            tokio::spawn(async move {
+                if let Err(e) = control::serve(api, handles).await {
+                    tracing::error!("Control API server failed: {e}");
+                }
+            });
+        }
+
+        // With leader election, only the replica holding the lease updates PDBs.
+        let elector = initial.leader_election.clone().map(|leader_election| {
+            Arc::new(LeaderElector::new(
+                client.clone(),
+                &initial.pod_namespace,
+                leader_election,
+            ))
+        });
+        let mut leader = elector.as_ref().map(|e| e.subscribe());
+        let election = elector.clone().map(|elector| {
+            let shutdown = ctx.shutdown.clone();
+            tokio::spawn(async move { elector.run(shutdown).await })
+        });
+        let is_leader = || elector.as_ref().is_none_or(|e| e.is_leader());
+
+        // Re-apply the state of PDBs that something else changes.
+        if initial.watch_pdbs && !initial.run_once {
+            for api in reconcile::watched_apis(&client, &initial) {
+                let (config, desired) = (config.clone(), ctx.desired.clone());
+                let (elector, shutdown) = (elector.clone(), ctx.shutdown.clone());
+                tokio::spawn(reconcile::watch(api, config, desired, elector, shutdown));
+            }
+        }
+
+        // Protect the servers on nodes that are about to be drained.
+        if initial.drain_aware {
+            let (cordoned, shutdown) = (ctx.cordoned.clone(), ctx.shutdown.clone());
+            tokio::spawn(drain::watch(client.clone(), cordoned, shutdown));
+        }
+
+        // Exit if the loop below stalls, so that the pod is restarted.
+        let watchdog = Arc::new(Watchdog::new());
+        if !initial.run_once {
+            let (watchdog, config) = (watchdog.clone(), config.clone());
+            let (elector, shutdown) = (elector.clone(), ctx.shutdown.clone());
+            tokio::spawn(watchdog::run(watchdog, config, elector, shutdown));
+        }
+
+        // The last known state of each PDB, by name.
+        let mut states: HashMap<String, ServerState> = HashMap::new();
+        // Set when the known states can't be trusted, e.g. because another
+        // replica held the lease in the meantime.
+        let reset_states = Cell::new(false);
+        // A dry run only pretends to patch, so its states are made up.
+        let mut was_dry_run = initial.dry_run;
+        let mut in_maintenance = false;
+        // When the last maintenance window ended, while its cooldown lasts.
+        let mut maintenance_ended: Option<Instant> = None;
+        // The servers last discovered by label.
+        let mut discovered: Vec<ServerConfig> = Vec::new();
+        // States saved by a previous run, used in place of the PDB's state.
+        let mut restored = match &initial.state_file {
+            Some(file) => persist::load(file).unwrap_or_else(|e| {
+                tracing::warn!("{e:#}");
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+        let mut saver = persist::Saver::new(persist::snapshot(&restored));
+
+        // Wrap the update method in an error printer. The latest configuration is
+        // read each time so that reloads apply on the next update.
+        let mut do_update = async || {
+            ctx.stats.record_cycle();
+            let mut config = config.read().unwrap().clone();
+            health.set_max_age(health_max_age(&config));
+            let client = ctx.client();
+
+            // Re-list on every update to pick up new PDBs, keeping the last known
+            // ones if that fails.
+            match discovery::discover(&client, &config).await {
+                Ok(found) => discovered = found,
+                Err(e) => tracing::warn!("{e:#}, using the last discovered PDBs."),
+            }
+            config.servers.extend(discovered.iter().cloned());
+
+            let window = config.maintenance_window.as_ref();
+            let now_in_maintenance = window.is_some_and(|w| w.is_active(chrono::Utc::now()));
+            let cooldown = window.map_or(Duration::ZERO, |w| w.cooldown);
+            if now_in_maintenance != in_maintenance {
+                if now_in_maintenance {
+                    tracing::info!("Entering maintenance window, allowing disruption.");
+                    maintenance_ended = None;
+                } else if !cooldown.is_zero() {
+                    tracing::info!(
+                        "Leaving maintenance window, still allowing disruption for {cooldown:?}."
+                    );
+                    maintenance_ended = Some(Instant::now());
+                } else {
+                    tracing::info!("Leaving maintenance window, resuming normal operation.");
+                }
+                in_maintenance = now_in_maintenance;
+            }
+            // Protection stays off until the cooldown is over, so as not to
+            // interrupt drains that started during the window.
+            let cooling_down = match maintenance_ended {
+                Some(ended) => {
+                    let left = cooldown.saturating_sub(ended.elapsed());
+                    if left.is_zero() {
+                        tracing::info!("Maintenance cooldown over, resuming normal operation.");
+                        maintenance_ended = None;
+                    } else {
+                        tracing::info!(
+                            "In maintenance cooldown, allowing disruption for {left:?}."
+                        );
+                    }
+                    !left.is_zero()
+                }
+                None => false,
+            };
+
+            if was_dry_run && !config.dry_run {
+                tracing::info!("Dry run ended, reading the current state of every PDB.");
+                reset_states.set(true);
+            }
+            was_dry_run = config.dry_run;
+            if reset_states.take() {
+                states.clear();
+            }
+            // Drop servers that were removed and check the initial state of new ones.
+            states.retain(|pdb_name, _| config.servers.iter().any(|s| &s.key() == pdb_name));
+            ctx.status
+                .retain(|pdb_name| config.servers.iter().any(|s| s.key() == pdb_name));
+            ctx.desired
+                .retain(|pdb_name| config.servers.iter().any(|s| s.key() == pdb_name));
+            for server in &config.servers {
+                if !states.contains_key(&server.key()) {
+                    if let Some(state) = restored.remove(&server.key()) {
+                        tracing::info!(
+                            pdb_name = server.key(),
+                            "Restored state from the state file."
+                        );
+                        ctx.status
+                            .record_initial(&server.key(), state.last_has_players);
+                        ctx.desired.set(&server.key(), state.last_has_players);
+                        states.insert(server.key(), state);
+                        continue;
+                    }
+                    let target = target::for_server(&client, &config, server);
+                    let pdb_name = &server.key();
+                    let initial = initial_protected(target.as_ref(), &config, pdb_name, &ctx).await;
+                    let initial = match initial {
+                        Ok(state) => state,
+                        Err(e) if e.is_not_found() => {
+                            tracing::warn!(pdb_name, "{e} Treating the server as unprotected.");
+                            false
+                        }
+                        Err(e) => {
+                            tracing::warn!(
+                                pdb_name,
+                                "{e}, giving up on reading the initial state and treating the server as unprotected."
+                            );
+                            false
+                        }
+                    };
+                    ctx.status.record_initial(pdb_name, initial);
+                    ctx.desired.set(pdb_name, initial);
+                    let mut state = ServerState::new(initial);
+                    // Protect the server until a query shows that it is empty.
+                    if config.startup_assume_players && !initial {
+                        let change = Change {
+                            has_players: true,
+                            players: None,
+                            reason: "StartupAssumePlayers",
+                            note: "Protecting the server until a query shows that it is empty."
+                                .to_string(),
+                        };
+                        let target = target.as_ref();
+                        if let Err(e) =
+                            commit_change(target, &config, server, &ctx, &mut state, change).await
+                        {
+                            tracing::warn!(pdb_name, "{e}");
+                        }
+                    }
+                    states.insert(server.key(), state);
+                }
+            }
+
+            // Servers are updated concurrently so that one unreachable server
+            // doesn't hold up the rest, but only so many at once.
+            let started = Instant::now();
+            let unauthorized = Cell::new(false);
+            let results: Vec<_> =
+                futures::stream::iter(states.iter_mut().filter_map(|(pdb_name, state)| {
+                    let server = config.servers.iter().find(|s| &s.key() == pdb_name)?;
+                    let (client, config, health, ctx) = (&client, &config, &health, &ctx);
+                    let unauthorized = &unauthorized;
+                    // Sources and targets are built from the latest configuration.
+                    let source = source::for_server(config, server, ctx.srv.as_ref(), &ctx.http);
+                    let target = target::for_server(client, config, server);
+                    Some(async move {
+                        let (source, target) = (source.as_ref(), target.as_ref());
+                        // A missing PDB is only tried again once its backoff is over.
+                        if let Some(left) = state.not_found_wait() {
+                            tracing::debug!(pdb_name, "Still not found, next attempt in {left:?}.");
+                            return (false, None);
+                        }
+                        let Some(result) = update_through_breaker(
+                            target,
+                            config,
+                            server,
+                            source,
+                            ctx,
+                            state,
+                            in_maintenance || cooling_down,
+                        )
+                        .await
+                        else {
+                            return (false, None);
+                        };
+                        match result {
+                            Ok(next_update) => {
+                                if state.record_found() {
+                                    tracing::info!(
+                                        pdb_name,
+                                        "Update succeeded, resuming normal updates."
+                                    );
+                                }
+                                health.mark_success();
+                                (true, next_update)
+                            }
+                            Err(e)
+                                if e.is_not_found() && !config.not_found_backoff_max.is_zero() =>
+                            {
+                                let delay = state.record_not_found(
+                                    Duration::from_secs(config.update_interval),
+                                    config.not_found_backoff_max,
+                                );
+                                tracing::warn!(pdb_name, "{e} Trying again in {delay:?}.");
+                                (false, None)
+                            }
+                            Err(e) => {
+                                tracing::warn!(pdb_name, "{e}");
+                                unauthorized.set(unauthorized.get() || e.is_unauthorized());
+                                (false, None)
+                            }
+                        }
+                    })
+                }))
+                .buffer_unordered(config.poll_concurrency)
+                .collect()
+                .await;
+            let elapsed = started.elapsed();
+            ctx.status.record_cycle(elapsed);
+            tracing::debug!("Updated {} server(s) in {elapsed:?}.", results.len());
+            // A rotated token is only read again by a new client, so build one
+            // before the next update.
+            if unauthorized.get() {
+                ctx.reauthenticate(&config).await;
+            }
+
+            // Save the states whenever they change, and often enough that an
+            // unchanged state is still fresh enough to be used.
+            if let Some(file) = &config.state_file
+                && let Err(e) = saver.save(file, persist::snapshot(&states))
+            {
+                tracing::warn!("{e:#}");
+            }
+            // With adaptive polling, the server closest to its threshold decides.
+            let next_update = results.iter().filter_map(|(_, next)| *next).min();
+            let next_update = match next_update {
+                Some(next_update) => {
+                    tracing::debug!("Next update in {next_update:?}.");
+                    next_update
+                }
+                None => Duration::from_secs(config.update_interval),
+            };
+            (next_update, results.iter().all(|(ok, _)| *ok))
+        };
+
+        // In once mode, a single update is made and its outcome is the exit code.
+        if initial.run_once {
+            let (_, ok) = do_update().instrument(tracing::info_span!("update")).await;
+            return if ok {
+                Ok(())
+            } else {
+                Err(anyhow!("Failed to update every PDB."))
+            };
+        }
+
+        // Now start running. The first update happens immediately.
+        let mut next_update = Duration::ZERO;
+        let mut rng: StdRng = match initial.interval_jitter_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => rand::make_rng(),
+        };
+        let mut shutdown_ok = true;
+        loop {
+            tokio::select! {
+                // Shut down if we received a signal.
+                _ = ctx.shutdown.cancelled() => {
+                    tracing::info!("Shutting down.");
+                    if is_leader() {
+                        // A stuck patch mustn't hold up termination until the pod
+                        // is killed.
+                        let config = config.read().unwrap().clone();
+                        let timeout = config.shutdown_timeout;
+                        shutdown_ok = tokio::time::timeout(timeout, apply_shutdown_state(&ctx.client(), &config))
+                            .await
+                            .unwrap_or_else(|_| {
+                                tracing::warn!("Shutdown state not applied within {timeout:?}, exiting anyway.");
+                                false
+                            });
+                    }
+                    ctx.stats.log_summary();
+                    break;
+                },
+                // Stop updating as soon as the lease is lost, even part-way
+                // through an update, and start again once it is reacquired.
+                _ = regain_leadership(&mut leader) => {
+                    reset_states.set(true);
+                    next_update = Duration::ZERO;
+                },
+                // The main loop.
+                (interval, _) = async {
+                    tokio::select! {
+                        _ = tokio::time::sleep(next_update) => {}
+                        _ = force_update.notified() => {}
+                    }
+                    do_update().instrument(tracing::info_span!("update")).await
+                }, if is_leader() => {
+                    watchdog.beat();
+                    let jitter = config.read().unwrap().interval_jitter;
+                    next_update = jittered(interval, jitter, &mut rng);
+                }
+            }
+        }
+
+        if let (Some(elector), Some(election)) = (elector, election) {
+            let _ = election.await;
+            if let Err(e) = elector.release().await {
+                tracing::warn!("Failed to release lease: {e}");
+            }
+        }
+
+        if shutdown_ok {
+            Ok(())
+        } else {
+            Err(anyhow!("Failed to apply the shutdown state to every PDB."))
+        }
+    }
+}
+
+/// Long-lived state shared by every update.
+pub struct Context {
+    pub shutdown: CancellationToken,
+    /// Replaced when the cluster rejects its credentials.
+    client: RwLock<Client>,
+    pub srv: Option<Arc<SrvResolver>>,
+    recorder: RwLock<Recorder>,
+    pub http: reqwest::Client,
+    pub status: Arc<Status>,
+    /// The state each PDB was last set to, for re-applying it on drift.
+    pub desired: Arc<Desired>,
+    /// The nodes that are cordoned, if watched.
+    pub cordoned: Arc<Cordoned>,
+    pub stats: Stats,
+}
+
+impl Context {
+    /// Set up the state of a new run, cancelled through `shutdown`.
+    pub fn new(client: Client, config: &Config, shutdown: CancellationToken) -> Result<Self> {
+        Ok(Context {
+            shutdown,
+            srv: if config.resolve_srv {
+                Some(Arc::new(SrvResolver::new()?))
+            } else {
+                None
+            },
+            recorder: RwLock::new(Recorder::new(client.clone(), env!("CARGO_PKG_NAME").into())),
+            client: RwLock::new(client),
+            http: reqwest::Client::new(),
+            status: Arc::new(Status::default()),
+            desired: Arc::new(Desired::default()),
+            cordoned: Arc::new(Cordoned::default()),
+            stats: Stats::new(),
+        })
+    }
+
+    /// The client to reach the cluster with.
+    pub fn client(&self) -> Client {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Rebuild the client from `config`, which reads the service account
+    /// token again, keeping the current one if that fails.
+    pub async fn reauthenticate(&self, config: &Config) {
+        tracing::warn!("Kubernetes rejected the client's credentials, re-authenticating.");
+        let result = async {
+            let client = build_kube_client(config).await?;
+            client.apiserver_version().await?;
+            anyhow::Ok(client)
+        };
+        match result.await {
+            Ok(client) => {
+                *self.recorder.write().unwrap() =
+                    Recorder::new(client.clone(), env!("CARGO_PKG_NAME").into());
+                *self.client.write().unwrap() = client;
+                tracing::info!("Re-authenticated with Kubernetes.");
+            }
+            Err(e) => tracing::error!(
+                "Failed to re-authenticate with Kubernetes, keeping the current client: {e:#}"
+            ),
+        }
+    }
+}
+
+/// Query a server, retrying with exponential backoff and jitter on failure.
+#[tracing::instrument(
+    name = "query",
+    skip_all,
+    fields(pdb_name = server.key(), server_host = server.server_host)
+)]
+pub async fn query_with_retry(
+    config: &Config,
+    server: &ServerConfig,
+    source: &dyn PlayerSource,
+    ctx: &Context,
+) -> Result<PlayerInfo, MgrError> {
+    let (retry, shutdown) = (&config.query_retry, &ctx.shutdown);
+    let mut attempt: u32 = 0;
+    loop {
+        // Timing every attempt tells a slow server apart from an unreachable
+        // one, which fails quickly.
+        let start = Instant::now();
+        let result = source
+            .player_info()
+            .await
+            .and_then(|info| check_reading(config, server, info))
+            .map_err(|e| {
+                let host = server.server_host.clone();
+                if e.is::<Starting>() {
+                    MgrError::ServerStarting {
+                        host,
+                        reason: e.to_string(),
+                    }
+                } else {
+                    MgrError::QueryFailed {
+                        host,
+                        source: e.into(),
+                    }
+                }
+            });
+        let latency = start.elapsed();
+        ctx.status.record_latency(&server.key(), latency);
+        tracing::debug!(pdb_name = server.key(), "Query took {latency:?}.");
+        match result {
+            Ok(info) => return Ok(info),
+            Err(e) if attempt < retry.retries && !shutdown.is_cancelled() => {
+                let delay = retry.delay(attempt);
+                attempt += 1;
+                tracing::debug!(
+                    pdb_name = server.key(),
+                    "Status query failed ({e}), retry {attempt}/{} in {delay:?}.",
+                    retry.retries
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => {
+                        ctx.stats.record_query_failure();
+                        return Err(e);
+                    }
+                }
+            }
+            Err(e) => {
+                ctx.stats.record_query_failure();
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Update a server unless its circuit breaker is open, returning `None` if it
+/// was skipped. Once the cooldown is over, a single query probes the server,
+/// and the breaker closes or opens again depending on how it went.
+async fn update_through_breaker(
+    target: &dyn ProtectionTarget,
+    config: &Config,
+    server: &ServerConfig,
+    source: &dyn PlayerSource,
+    ctx: &Context,
+    state: &mut ServerState,
+    in_maintenance: bool,
+) -> Option<Result<Option<Duration>, MgrError>> {
+    let pdb_name = &server.key();
+    // A server that keeps failing is skipped until its cooldown is over.
+    if let Some(left) = state.breaker_wait() {
+        tracing::debug!(pdb_name, "Circuit breaker open, next probe in {left:?}.");
+        return None;
+    }
+    let probe_config;
+    let config = if state.breaker() == BreakerState::HalfOpen {
+        tracing::info!(pdb_name, "Circuit breaker half-open, probing the server.");
+        ctx.status.record_breaker(pdb_name, BreakerState::HalfOpen);
+        probe_config = Config {
+            query_retry: RetryConfig {
+                retries: 0,
+                ..config.query_retry.clone()
+            },
+            ..config.clone()
+        };
+        &probe_config
+    } else {
+        config
+    };
+    let result = try_update_pdb(target, config, server, source, ctx, state, in_maintenance).await;
+    // Any successful query closes the breaker, even if the patch then fails.
+    if (result.is_ok() || state.failures() == 0) && state.reset_breaker() {
+        tracing::info!(
+            pdb_name,
+            "Circuit breaker closed, querying the server again."
+        );
+        ctx.status.record_breaker(pdb_name, BreakerState::Closed);
+    }
+    // A missing PDB is backed off from instead, if enabled.
+    if let Err(e) = &result
+        && (!e.is_not_found() || config.not_found_backoff_max.is_zero())
+        && let Some(breaker) = &config.circuit_breaker
+        && state.failures() >= breaker.failures
+    {
+        let cooldown = state.trip_breaker(breaker);
+        tracing::warn!(
+            pdb_name,
+            "Circuit breaker open after {} failed queries in a row, skipping the server for {cooldown:?}.",
+            state.failures()
+        );
+        ctx.status.record_breaker(pdb_name, BreakerState::Open);
+    }
+    Some(result)
+}
+
+/// Reject a reading that can't be right, such as from a spoofed status, so
+/// that it is handled like a failed query instead of deciding the state.
+fn check_reading(config: &Config, server: &ServerConfig, info: PlayerInfo) -> Result<PlayerInfo> {
+    let pdb_name = server.key();
+    if let Some(sane) = config.max_sane_players
+        && info.online > sane
+    {
+        tracing::warn!(
+            pdb_name,
+            "Server reported {} players online, more than MAX_SANE_PLAYERS ({sane}).",
+            info.online
+        );
+        bail!("implausible reading of {} players online", info.online);
+    }
+    // A max of 0 is what sources that don't know it report.
+    if info.max == 0 || info.online <= info.max {
+        return Ok(info);
+    }
+    match config.over_max_players {
+        OverMaxPlayers::Allow => Ok(info),
+        OverMaxPlayers::Clamp => {
+            tracing::warn!(
+                pdb_name,
+                "Server reported {}/{} players, counting only {}.",
+                info.online,
+                info.max,
+                info.max
+            );
+            Ok(PlayerInfo {
+                online: info.max,
+                ..info
+            })
+        }
+        OverMaxPlayers::Reject => {
+            tracing::warn!(
+                pdb_name,
+                "Server reported {}/{} players, more than its max.",
+                info.online,
+                info.max
+            );
+            bail!(
+                "implausible reading of {}/{} players",
+                info.online,
+                info.max
+            );
+        }
+    }
+}
+
+/// The name of the span of each server's update, with its `pdb_name` and
+/// `server_host`.
+pub const UPDATE_SPAN: &str = "update_pdb";
+
+/// Update a server's PDB, returning when it should next be updated if adaptive
+/// polling is enabled.
+#[tracing::instrument(
+    name = "update_pdb",
+    skip_all,
+    fields(
+        pdb_name = server.key(),
+        server_host = server.server_host,
+        has_players = tracing::field::Empty
+    )
+)]
+pub async fn try_update_pdb(
+    target: &dyn ProtectionTarget,
+    config: &Config,
+    server: &ServerConfig,
+    source: &dyn PlayerSource,
+    ctx: &Context,
+    state: &mut ServerState,
+    in_maintenance: bool,
+) -> Result<Option<Duration>, MgrError> {
+    let pdb_name = &server.key();
+
+    // A paused PDB is left alone, e.g. during manual maintenance.
+    let annotations = target.current_annotations().await?;
+    if target::is_paused(&annotations) {
+        if state.set_paused(true) {
+            tracing::info!(pdb_name, "PodDisruptionBudget is paused, skipping updates.");
+        } else {
+            tracing::debug!(pdb_name, "PodDisruptionBudget is still paused.");
+        }
+        return Ok(None);
+    }
+    if state.set_paused(false) {
+        // It may have been changed by hand in the meantime.
+        let protected = target.current_protected().await?;
+        state.resync(protected);
+        ctx.desired.set(pdb_name, protected);
+        tracing::info!(
+            pdb_name,
+            "PodDisruptionBudget is no longer paused, resuming updates."
+        );
+    }
+
+    // Disruption is always allowed during a maintenance window.
+    if in_maintenance {
+        if state.last_has_players {
+            let change = Change {
+                has_players: false,
+                players: None,
+                reason: "MaintenanceWindow",
+                note: "Allowing disruption during the maintenance window.".to_string(),
+            };
+            commit_change(target, config, server, ctx, state, change).await?;
+        }
+        return Ok(None);
+    }
+
+    let info = match query_with_retry(config, server, source, ctx).await {
+        Ok(info) => {
+            state.record_success();
+            info
+        }
+        // A server that is starting is about to have players, unlike one that
+        // is down.
+        Err(e @ MgrError::ServerStarting { .. }) => {
+            tracing::Span::current().record("has_players", true);
+            if !state.observe(true, &config.debounce) {
+                tracing::debug!(pdb_name, "{e}, keeping protection while it starts.");
+                return Ok(None);
+            }
+            let change = Change {
+                has_players: true,
+                players: None,
+                reason: "ServerStarting",
+                note: format!("{e} - protecting the server while it starts."),
+            };
+            commit_change(target, config, server, ctx, state, change).await?;
+            tracing::info!(pdb_name, "Server is starting, protecting it.");
+            return Ok(None);
+        }
+        Err(e) => {
+            let failures = state.record_failure();
+            tracing::debug!(
+                pdb_name,
+                "{failures} consecutive query failures (threshold {}).",
+                config.failure_threshold
+            );
+            // Until the threshold is reached, the server isn't considered down.
+            if failures < config.failure_threshold {
+                tracing::info!(pdb_name, "Query failed, keeping the last known state.");
+                return Err(e);
+            }
+            state.record_down();
+            if config.startup_assume_players && !state.has_queried() {
+                tracing::info!(
+                    pdb_name,
+                    "Query failed, keeping protection until the first successful query."
+                );
+                return Err(e);
+            }
+            let has_players = match config.query_failure_mode {
+                QueryFailureMode::Keep => {
+                    tracing::info!(pdb_name, "Query failed, keeping the current state.");
+                    return Err(e);
+                }
+                QueryFailureMode::Protect => true,
+                QueryFailureMode::Allow => false,
+            };
+            let action = if has_players {
+                "protecting the server"
+            } else {
+                "allowing disruption"
+            };
+            if has_players != state.last_has_players {
+                let change = Change {
+                    has_players,
+                    players: None,
+                    reason: "QueryFailed",
+                    note: format!("{e} - {action}."),
+                };
+                commit_change(target, config, server, ctx, state, change).await?;
+            }
+            tracing::info!(pdb_name, "Query failed, {action}.");
+            return Err(e);
+        }
+    };
+    let players_max = info.max;
+    if config.log_player_names
+        && let Some(names) = &info.names
+    {
+        tracing::debug!(pdb_name, "Online players ({}): {names:?}", info.online);
+    }
+    // Only count the players that pass the filter, if there is one.
+    let players_online = match (&config.player_filter, &info.names) {
+        (Some(filter), Some(names)) => {
+            let counted = names.iter().filter(|n| filter.counts(n)).count();
+            tracing::debug!(
+                pdb_name,
+                "Counting {counted} of {} online players: {names:?}",
+                info.online
+            );
+            u32::try_from(counted).unwrap_or(u32::MAX)
+        }
+        _ => info.online,
+    };
+    // The PDB's annotations override the server's threshold, and a scheduled
+    // threshold takes the place of both. A single player overrides them all if
+    // configured. Which of them applies is shown on `/status`.
+    let (threshold, mut source) = if config.protect_if_any_players {
+        (Threshold::Absolute(1), "PROTECT_IF_ANY_PLAYERS".to_string())
+    } else {
+        let threshold = target::annotated_threshold(&annotations, &server.threshold)
+            .unwrap_or_else(|e| {
+                tracing::warn!(pdb_name, "Ignoring threshold annotations: {e}.");
+                server.threshold.clone()
+            });
+        let scheduled = config
+            .threshold_schedule
+            .as_ref()
+            .and_then(|schedule| schedule.active(chrono::Utc::now()));
+        match scheduled {
+            Some((i, min_players)) => (
+                Threshold::Absolute(min_players),
+                format!("THRESHOLD_SCHEDULE rule {i}"),
+            ),
+            None if threshold != server.threshold => (threshold, "annotations".to_string()),
+            None => (threshold, "threshold".to_string()),
+        }
+    };
+    // The absolute part of a combined threshold is what a server without a
+    // max falls back to.
+    let fallback = match threshold {
+        Threshold::Absolute(n) | Threshold::Or(n, _) | Threshold::And(n, _) => n,
+        Threshold::Percent(_) => DEFAULT_MIN_PLAYERS,
+    };
+    // Any percentage of zero is zero, which every server would meet.
+    let percent_needed = |pct: f64| -> Result<(f64, String), MgrError> {
+        if players_max > 0 {
+            let req: f64 = Percentage::from_decimal(pct).apply_to(players_max.into());
+            return Ok((req, format!("{:.0}% [{}]", pct * 100.0, req as i32)));
+        }
+        match config.zero_max_players {
+            ZeroMaxPlayers::Error => Err(MgrError::ZeroMaxPlayers {
+                host: server.server_host.clone(),
+                percent: pct * 100.0,
+            }),
+            ZeroMaxPlayers::MinPlayers => {
+                tracing::warn!(
+                    pdb_name,
+                    "Server reported a max of 0 players, falling back to {fallback} players."
+                );
+                Ok((f64::from(fallback), format!("{fallback}")))
+            }
+        }
+    };
+    // Protection rules replace the threshold altogether.
+    let (players_needed, need_msg): (f64, String) = match &config.protection_rules {
+        Some(rules) => {
+            source = "PROTECTION_RULES".to_string();
+            rules.players_needed(players_max, chrono::Utc::now())
+        }
+        None => match threshold {
+            Threshold::Percent(pct) => percent_needed(pct)?,
+            Threshold::Absolute(min_players) => (f64::from(min_players), format!("{min_players}")),
+            // Meeting either requirement means meeting the lower one.
+            Threshold::Or(min_players, pct) => {
+                let (req, msg) = percent_needed(pct)?;
+                (
+                    f64::from(min_players).min(req),
+                    format!("{min_players} OR {msg}"),
+                )
+            }
+            Threshold::And(min_players, pct) => {
+                let (req, msg) = percent_needed(pct)?;
+                (
+                    f64::from(min_players).max(req),
+                    format!("{min_players} AND {msg}"),
+                )
+            }
+        },
+    };
+    // Any player at all is always enough with PROTECT_IF_ANY_PLAYERS.
+    let inclusive = config.threshold_inclusive || config.protect_if_any_players;
+    let has_players = meets_threshold(players_online, players_needed, inclusive);
+    let op = if inclusive { ">=" } else { ">" };
+    tracing::Span::current().record("has_players", has_players);
+    ctx.status.record_query(
+        pdb_name,
+        (players_online, players_max),
+        players_needed,
+        &need_msg,
+        &source,
+    );
+    let next_update = config
+        .adaptive_interval
+        .as_ref()
+        .map(|a| a.interval(players_online, players_needed));
+
+    tracing::debug!(
+        pdb_name,
+        players_online,
+        players_max,
+        has_players,
+        "Condition {}: {players_online}/{players_max} players (need {op} {need_msg}).",
+        if has_players { "met" } else { "unmet" }
+    );
+    if let Some(key) = &config.pod_annotation
+        && let Err(e) =
+            annotate::annotate_pods(&ctx.client(), config, key, server, players_online).await
+    {
+        tracing::warn!(pdb_name, "{e:#}");
+    }
+
+    // A server that only just came up stays protected regardless, so that a
+    // crash-looping server isn't drained over and over.
+    let starting = state
+        .uptime()
+        .and_then(|uptime| config.min_uptime.checked_sub(uptime))
+        .filter(|left| !left.is_zero());
+    if let Some(left) = starting.filter(|_| !has_players) {
+        tracing::debug!(
+            pdb_name,
+            "Server hasn't been up for {:?} yet, keeping protection for {left:?}.",
+            config.min_uptime
+        );
+    }
+    // As does one that had players recently, so that a lull between waves of
+    // activity doesn't allow disruption.
+    if has_players {
+        state.record_active();
+    }
+    let since_active = state.since_active();
+    let recently_active = since_active
+        .and_then(|since| config.recent_activity.checked_sub(since))
+        .filter(|left| !left.is_zero());
+    if let (Some(since), Some(left)) = (since_active, recently_active.filter(|_| !has_players)) {
+        tracing::debug!(
+            pdb_name,
+            "Server last had players {since:?} ago, keeping protection for {left:?}."
+        );
+    }
+    // A server on a cordoned node is about to be evicted, so it stays
+    // protected with anyone on it, until it has been empty for a while.
+    state.record_online(players_online);
+    let draining = if config.drain_aware {
+        drain::cordoned_node(&ctx.client(), config, server, &ctx.cordoned)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!(pdb_name, "Failed to check for a cordoned node: {e:#}");
+                None
+            })
+            .filter(|_| {
+                state
+                    .empty_for()
+                    .is_none_or(|empty| empty < config.drain_empty)
+            })
+    } else {
+        None
+    };
+    if let Some(node) = draining.as_ref().filter(|_| !has_players) {
+        tracing::debug!(
+            pdb_name,
+            "Node {node} is cordoned, keeping protection until the server has been empty for {:?}.",
+            config.drain_empty
+        );
+    }
+    let protect =
+        has_players || starting.is_some() || draining.is_some() || recently_active.is_some();
+
+    if !state.observe(protect, &config.debounce) {
+        if protect == state.last_has_players {
+            tracing::debug!(
+                pdb_name,
+                "Server player state unchanged - skipping this update."
+            );
+        } else {
+            tracing::debug!(
+                pdb_name,
+                "Server player state changed, debouncing ({} cycles so far).",
+                state.pending_cycles()
+            );
+        }
+        return Ok(next_update);
+    }
+
+    let (reason, action) = if has_players {
+        ("PlayersOnline", "protecting the server".to_string())
+    } else if starting.is_some() {
+        (
+            "MinimumUptime",
+            format!(
+                "protecting the server until it has been up for {:?}",
+                config.min_uptime
+            ),
+        )
+    } else if let Some(node) = &draining {
+        (
+            "NodeDraining",
+            format!("protecting the server while its node {node} is cordoned"),
+        )
+    } else if protect {
+        (
+            "RecentActivity",
+            format!(
+                "protecting the server until it has been empty for {:?}",
+                config.recent_activity
+            ),
+        )
+    } else {
+        ("NoPlayers", "allowing disruption".to_string())
+    };
+    let change = Change {
+        has_players: protect,
+        players: Some((players_online, players_max)),
+        reason,
+        note: format!(
+            "{players_online}/{players_max} players online (need {op} {need_msg}), {action}."
+        ),
+    };
+    commit_change(target, config, server, ctx, state, change).await?;
+    Ok(next_update)
+}
+
+/// Whether `online` players meet a threshold of `needed` players, which is
+/// met by exactly that many only if it is `inclusive`.
+fn meets_threshold(online: u32, needed: f64, inclusive: bool) -> bool {
+    if inclusive {
+        f64::from(online) >= needed
+    } else {
+        f64::from(online) > needed
+    }
+}
+
+/// Read whether a new server is protected, retrying transient errors so
+/// that they don't lead to a wrong initial state.
+async fn initial_protected(
+    target: &dyn ProtectionTarget,
+    config: &Config,
+    pdb_name: &str,
+    ctx: &Context,
+) -> Result<bool, MgrError> {
+    let (retry, shutdown) = (&config.initial_get_retry, &ctx.shutdown);
+    let mut attempt: u32 = 0;
+    loop {
+        match target.current_protected().await {
+            // A missing PDB won't appear by asking again.
+            Err(e) if !e.is_not_found() && attempt < retry.retries && !shutdown.is_cancelled() => {
+                let delay = retry.delay(attempt);
+                attempt += 1;
+                tracing::warn!(
+                    pdb_name,
+                    "{e}, retry {attempt}/{} in {delay:?}.",
+                    retry.retries
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.cancelled() => return Err(e),
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A change in a server's player state, and why it was made.
+pub struct Change {
+    pub has_players: bool,
+    /// The online and max players, unless the change wasn't caused by them.
+    pub players: Option<(u32, u32)>,
+    /// The reason of the Event, in PascalCase.
+    pub reason: &'static str,
+    pub note: String,
+}
+
+/// Protect a server or allow disruption, and report the change.
+#[tracing::instrument(
+    name = "patch",
+    skip_all,
+    fields(pdb_name = server.key(), has_players = change.has_players)
+)]
+pub async fn commit_change(
+    target: &dyn ProtectionTarget,
+    config: &Config,
+    server: &ServerConfig,
+    ctx: &Context,
+    state: &mut ServerState,
+    change: Change,
+) -> Result<(), MgrError> {
+    let pdb_name = &server.key();
+    // The changes DIRECTION leaves out are up to an operator to make.
+    if !config.direction.allows(change.has_players) {
+        tracing::info!(
+            pdb_name,
+            "Would {} ({}), but DIRECTION doesn't allow it.",
+            if change.has_players {
+                "protect the server"
+            } else {
+                "allow disruption"
+            },
+            change.reason
+        );
+        return Ok(());
+    }
+    // Allowing disruption is deferred until long enough after the last patch,
+    // and then made with whatever state is current. Protection never waits,
+    // which still bounds the patches to two per interval.
+    if !change.has_players
+        && let Some(elapsed) = state
+            .since_last_patch()
+            .filter(|elapsed| *elapsed < config.min_patch_interval)
+    {
+        tracing::debug!(
+            pdb_name,
+            "Last patched {elapsed:?} ago, deferring the change for up to {:?}.",
+            config.min_patch_interval - elapsed
+        );
+        return Ok(());
+    }
+    // The watcher must not undo the patch once it sees it.
+    ctx.desired.set(pdb_name, change.has_players);
+    let result = set_protected(target, config, pdb_name, change.has_players).await;
+    if !config.dry_run {
+        ctx.stats.record_patch(result.is_ok());
+    }
+    if let Err(e) = result {
+        ctx.desired.set(pdb_name, state.last_has_players);
+        return Err(e);
+    }
+    state.commit(change.has_players);
+    ctx.stats.record_transition();
+    ctx.status
+        .record_patch(pdb_name, change.has_players, config.dry_run);
+    notify_webhook(config, ctx, server, change.has_players, change.players).await;
+    publish_event(
+        config,
+        ctx,
+        target.event_object(),
+        change.reason,
+        change.note,
+    )
+    .await;
+    Ok(())
+}
+
+/// Protect a target or allow its disruption, retrying patches that conflict
+/// with another change to it.
+pub async fn set_protected(
+    target: &dyn ProtectionTarget,
+    config: &Config,
+    pdb_name: &str,
+    protected: bool,
+) -> Result<(), MgrError> {
+    let mut attempt: u32 = 0;
+    loop {
+        match target.set_protected(protected).await {
+            Err(e @ MgrError::PatchConflict { .. }) if attempt < config.patch_conflict_retries => {
+                attempt += 1;
+                let delay = config.patch_conflict_retry_delay;
+                tracing::warn!(
+                    pdb_name,
+                    "{e}, retry {attempt}/{} in {delay:?}.",
+                    config.patch_conflict_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Notify the webhook of a change in a server's player state, if configured.
+async fn notify_webhook(
+    config: &Config,
+    ctx: &Context,
+    server: &ServerConfig,
+    has_players: bool,
+    players: Option<(u32, u32)>,
+) {
+    // Nothing changed in a dry run.
+    let Some(webhook) = config.webhook.as_ref().filter(|_| !config.dry_run) else {
+        return;
+    };
+    let transition = webhook::Transition {
+        server,
+        has_players,
+        players,
+    };
+    webhook::notify(&ctx.http, webhook, &transition).await;
+}
+
+/// Record a change of a target's state as an Event on it, if enabled.
+async fn publish_event(
+    config: &Config,
+    ctx: &Context,
+    object: Option<ObjectReference>,
+    reason: &str,
+    note: String,
+) {
+    // Nothing was patched in a dry run, though a target may still hold the
+    // object of a patch from before it was enabled.
+    if !config.emit_events || config.dry_run {
+        return;
+    }
+    let Some(object) = object else {
+        return;
+    };
+    // Like UpdatePodDisruptionBudget, or UpdatePod for annotated pods.
+    let action = format!("Update{}", object.kind.as_deref().unwrap_or_default());
+    let event = Event {
+        type_: EventType::Normal,
+        reason: reason.to_string(),
+        note: Some(note),
+        action,
+        secondary: None,
+    };
+    let recorder = ctx.recorder.read().unwrap().clone();
+    if let Err(e) = recorder.publish(&event, &object).await {
+        tracing::warn!(pdb_name = object.name, "Failed to publish event: {e}");
+    }
+}
+
+/// Apply the configured shutdown state to every PDB, returning whether it was
+/// applied to all of them.
+pub async fn apply_shutdown_state(client: &Client, config: &Config) -> bool {
+    let protect = match config.shutdown_pdb_state {
+        ShutdownState::Protect => true,
+        ShutdownState::Allow => false,
+        ShutdownState::Leave => return true,
+    };
+    let mut servers = config.servers.clone();
+    let mut ok = true;
+    match discovery::discover(client, config).await {
+        Ok(discovered) => servers.extend(discovered),
+        Err(e) => {
+            tracing::warn!("{e:#}");
+            ok = false;
+        }
+    }
+    for server in &servers {
+        let target = target::for_server(client, config, server);
+        if target
+            .current_annotations()
+            .await
+            .is_ok_and(|a| target::is_paused(&a))
+        {
+            tracing::info!(pdb_name = server.key(), "Leaving paused PDB as it is.");
+            continue;
+        }
+        match target.set_protected(protect).await {
+            // The dry run was already logged.
+            Ok(()) if config.dry_run => {}
+            Ok(()) => tracing::info!(
+                pdb_name = server.key(),
+                "Set {} for shutdown.",
+                target.setting(protect)
+            ),
+            Err(e) => {
+                tracing::warn!("{e}");
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Wait until this instance loses the lease and then acquires it again. Never
+/// completes without leader election.
+async fn regain_leadership(leader: &mut Option<watch::Receiver<bool>>) {
+    match leader {
+        Some(leader) => {
+            let _ = leader.wait_for(|l| !*l).await;
+            let _ = leader.wait_for(|l| *l).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Randomly lengthen or shorten an interval by up to `jitter` of it.
+fn jittered(interval: Duration, jitter: f64, rng: &mut StdRng) -> Duration {
+    if jitter <= 0.0 {
+        return interval;
+    }
+    interval.mul_f64(1.0 + rng.random_range(-jitter..=jitter))
+}
+
+/// How old the last successful update may be before readiness fails: a few
+/// of the longest intervals.
+fn health_max_age(config: &Config) -> Duration {
+    config.longest_interval() * 3
+}
+
+fn log_thresholds(config: &Config) {
+    if config.protect_if_any_players {
+        tracing::info!(
+            "PROTECT_IF_ANY_PLAYERS is set, protecting every server while any player is online and ignoring its threshold."
+        );
+        return;
+    }
+    if let Some(rules) = &config.protection_rules {
+        tracing::info!(
+            "PROTECTION_RULES is set, protecting every server while any of its {} rule(s) match and ignoring its threshold.",
+            rules.rules.len()
+        );
+        return;
+    }
+    for server in &config.servers {
+        let pdb_name = &server.key();
+        match server.threshold {
+            Threshold::Percent(pct) => {
+                tracing::debug!(
+                    pdb_name,
+                    "Will watch for minimum {:.0}% of players.",
+                    pct * 100.0
+                );
+            }
+            Threshold::Absolute(min_players) => {
+                tracing::debug!(pdb_name, "Will watch for minimum {min_players} players.");
+            }
+            Threshold::Or(min_players, pct) => {
+                tracing::debug!(
+                    pdb_name,
+                    "Will watch for minimum {min_players} players or {:.0}% of players.",
+                    pct * 100.0
+                );
+            }
+            Threshold::And(min_players, pct) => {
+                tracing::debug!(
+                    pdb_name,
+                    "Will watch for minimum {min_players} players and {:.0}% of players.",
+                    pct * 100.0
+                );
+            }
+        }
+    }
+}
+
+/// Connect to the Kubernetes API, retrying with exponential backoff so that a
+/// control plane blip at startup doesn't crash-loop the pod.
+pub async fn kube_client(config: &Config) -> Result<Client> {
+    let retry = &config.kube_connect_retry;
+    let mut attempt: u32 = 0;
+    loop {
+        let result = async {
+            let client = build_kube_client(config).await?;
+            // Building the client doesn't connect, so check that it can.
+            client.apiserver_version().await?;
+            anyhow::Ok(client)
+        };
+        match result.await {
+            Ok(client) => return Ok(client),
+            Err(e) if attempt < retry.retries => {
+                let delay = retry.delay(attempt);
+                attempt += 1;
+                tracing::warn!(
+                    "Failed to connect to Kubernetes ({e:#}), retry {attempt}/{} in {delay:?}.",
+                    retry.retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(anyhow!("Failed to connect to Kubernetes: {e:#}")),
+        }
+    }
+}
+
+/// Build the Kube client from the configured kubeconfig and context, or from
+/// the cluster or default kubeconfig if neither is set.
+async fn build_kube_client(config: &Config) -> Result<Client> {
+    let uses_kubeconfig = config.kubeconfig_path.is_some() || config.kube_context.is_some();
+    let mut client_config = match &config.client_cert {
+        None if !uses_kubeconfig => return Ok(Client::try_default().await?),
+        // Without a service account, only the address of the API server is
+        // taken from the pod.
+        Some(_) if !uses_kubeconfig => {
+            let (Ok(host), Ok(port)) = (
+                std::env::var("KUBERNETES_SERVICE_HOST"),
+                std::env::var("KUBERNETES_SERVICE_PORT"),
+            ) else {
+                bail!(
+                    "KUBERNETES_SERVICE_HOST and KUBERNETES_SERVICE_PORT must be set to use a client certificate without KUBECONFIG_PATH"
+                );
+            };
+            let host = if host.contains(':') {
+                format!("[{host}]")
+            } else {
+                host
+            };
+            kube::Config::new(format!("https://{host}:{port}").parse()?)
+        }
+        _ => {
+            let options = KubeConfigOptions {
+                context: config.kube_context.clone(),
+                ..Default::default()
+            };
+            let kubeconfig = match &config.kubeconfig_path {
+                Some(path) => Kubeconfig::read_from(path)?,
+                None => Kubeconfig::read()?,
+            };
+            kube::Config::from_custom_kubeconfig(kubeconfig, &options).await?
+        }
+    };
+    if let Some(cert) = &config.client_cert {
+        // The certificate replaces whatever credentials the kubeconfig has.
+        client_config.auth_info = AuthInfo {
+            client_certificate: Some(cert.cert_path.clone()),
+            client_key: Some(cert.key_path.clone()),
+            ..Default::default()
+        };
+        if let Some(path) = &cert.ca_path {
+            let pem = std::fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+            let certs = CertificateDer::pem_slice_iter(&pem)
+                .map(|cert| cert.map(|cert| cert.to_vec()))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("Failed to parse {path}"))?;
+            client_config.root_cert = Some(certs);
+            client_config.root_cert_file = None;
+        }
+    }
+    Ok(Client::try_from(client_config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use clap::Parser;
+    use config::{CircuitBreaker, Cli};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A source with a single reading, failing if there is none.
+    struct Reading {
+        players: Option<(u32, u32)>,
+        queried: AtomicBool,
+    }
+
+    #[async_trait]
+    impl PlayerSource for Reading {
+        async fn player_info(&self) -> Result<PlayerInfo> {
+            self.queried.store(true, Ordering::Relaxed);
+            let (online, max) = self.players.ok_or_else(|| anyhow!("connection refused"))?;
+            Ok(PlayerInfo::counts(online, max))
+        }
+    }
+
+    /// A target that remembers every change made to it.
+    #[derive(Default)]
+    struct Patches(Mutex<Vec<bool>>);
+
+    #[async_trait]
+    impl ProtectionTarget for Patches {
+        async fn set_protected(&self, protected: bool) -> Result<(), MgrError> {
+            self.0.lock().unwrap().push(protected);
+            Ok(())
+        }
+
+        async fn current_protected(&self) -> Result<bool, MgrError> {
+            Ok(self.0.lock().unwrap().last().copied().unwrap_or_default())
+        }
+
+        fn setting(&self, protected: bool) -> String {
+            format!("protected={protected}")
+        }
+    }
+
+    /// A server that is updated with one reading at a time, without a network
+    /// or a cluster.
+    struct Harness {
+        config: Config,
+        ctx: Context,
+        target: Patches,
+        state: ServerState,
+    }
+
+    impl Harness {
+        fn new(args: &[&str], protected: bool) -> Self {
+            let base = [
+                "minecraft-pdb-mgr",
+                "--namespace=minecraft",
+                "--pdb-name=survival",
+                "--server-host=127.0.0.1",
+                "--server-port=25565",
+            ];
+            let cli = Cli::try_parse_from(base.iter().chain(args)).unwrap();
+            let config = Config::from_parts(cli, &HashMap::new()).unwrap();
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            // The client is only connected once it is used, which it isn't.
+            let url = "http://127.0.0.1:1".parse().unwrap();
+            let client = Client::try_from(kube::Config::new(url)).unwrap();
+            let ctx = Context::new(client, &config, CancellationToken::new()).unwrap();
+            Harness {
+                config,
+                ctx,
+                target: Patches::default(),
+                state: ServerState::new(protected),
+            }
+        }
+
+        /// Update the server with `players` online and max, or a failed query
+        /// if `None`, returning whether the server was queried.
+        async fn update(&mut self, players: Option<(u32, u32)>) -> bool {
+            let source = Reading {
+                players,
+                queried: AtomicBool::new(false),
+            };
+            let server = &self.config.servers[0];
+            let _ = update_through_breaker(
+                &self.target,
+                &self.config,
+                server,
+                &source,
+                &self.ctx,
+                &mut self.state,
+                false,
+            )
+            .await;
+            source.queried.into_inner()
+        }
+
+        fn patches(&self) -> Vec<bool> {
+            self.target.0.lock().unwrap().clone()
+        }
+
+        fn status(&self) -> serde_json::Value {
+            let key = self.config.servers[0].key();
+            self.ctx.status.to_json()["servers"][key].clone()
+        }
+    }
+
+    #[test]
+    fn meets_threshold_at_needed_only_if_inclusive() {
+        assert!(meets_threshold(5, 5.0, true));
+        assert!(!meets_threshold(5, 5.0, false));
+        assert!(!meets_threshold(4, 5.0, true));
+        assert!(meets_threshold(6, 5.0, false));
+        // A percentage of the max need not be whole.
+        assert!(meets_threshold(5, 4.5, false));
+        assert!(!meets_threshold(4, 4.5, true));
+        // No rule applies.
+        assert!(!meets_threshold(u32::MAX, f64::INFINITY, true));
+    }
+
+    #[tokio::test]
+    async fn protects_at_threshold() {
+        let mut h = Harness::new(&["--min-players=5"], false);
+        h.update(Some((4, 20))).await;
+        assert!(h.patches().is_empty());
+        h.update(Some((5, 20))).await;
+        assert_eq!(h.patches(), [true]);
+        h.update(Some((4, 20))).await;
+        assert_eq!(h.patches(), [true, false]);
+        assert!(!h.state.last_has_players);
+    }
+
+    #[tokio::test]
+    async fn protects_above_exclusive_threshold() {
+        let mut h = Harness::new(&["--min-players=5", "--threshold-inclusive=false"], false);
+        h.update(Some((5, 20))).await;
+        assert!(h.patches().is_empty());
+        h.update(Some((6, 20))).await;
+        assert_eq!(h.patches(), [true]);
+    }
+
+    #[tokio::test]
+    async fn protects_at_percent_threshold() {
+        let mut h = Harness::new(&["--min-players-percent=0.5"], false);
+        h.update(Some((9, 20))).await;
+        assert!(h.patches().is_empty());
+        h.update(Some((10, 20))).await;
+        assert_eq!(h.patches(), [true]);
+        // The same count is below half of a larger server.
+        h.update(Some((10, 40))).await;
+        assert_eq!(h.patches(), [true, false]);
+    }
+
+    #[tokio::test]
+    async fn skips_unchanged_state() {
+        let mut h = Harness::new(&["--min-players=5"], true);
+        for online in [5, 9, 20, 5] {
+            h.update(Some((online, 20))).await;
+        }
+        assert!(h.patches().is_empty());
+        assert!(h.state.last_has_players);
+    }
+
+    #[tokio::test]
+    async fn debounces_allowing_disruption() {
+        let mut h = Harness::new(&["--min-players=5", "--debounce-cycles=2"], true);
+        h.update(Some((0, 20))).await;
+        // Seeing players again starts the count over.
+        h.update(Some((5, 20))).await;
+        h.update(Some((0, 20))).await;
+        assert!(h.patches().is_empty());
+        h.update(Some((0, 20))).await;
+        assert_eq!(h.patches(), [false]);
+        // Protection isn't debounced.
+        h.update(Some((5, 20))).await;
+        assert_eq!(h.patches(), [false, true]);
+    }
+
+    #[tokio::test]
+    async fn keeps_state_on_failure_by_default() {
+        for protected in [false, true] {
+            let mut h = Harness::new(&["--min-players=5"], protected);
+            h.update(None).await;
+            assert!(h.patches().is_empty());
+            assert_eq!(h.state.last_has_players, protected);
+        }
+    }
+
+    #[tokio::test]
+    async fn protects_on_failure() {
+        let args = ["--min-players=5", "--query-failure-mode=protect"];
+        let mut h = Harness::new(&args, false);
+        h.update(None).await;
+        assert_eq!(h.patches(), [true]);
+    }
+
+    #[tokio::test]
+    async fn allows_disruption_on_failure() {
+        let args = ["--min-players=5", "--query-failure-mode=allow"];
+        let mut h = Harness::new(&args, true);
+        h.update(None).await;
+        assert_eq!(h.patches(), [false]);
+    }
+
+    #[tokio::test]
+    async fn waits_for_failure_threshold() {
+        let args = [
+            "--min-players=5",
+            "--query-failure-mode=protect",
+            "--failure-threshold=2",
+        ];
+        let mut h = Harness::new(&args, false);
+        h.update(None).await;
+        assert!(h.patches().is_empty());
+        h.update(None).await;
+        assert_eq!(h.patches(), [true]);
+    }
+
+    #[tokio::test]
+    async fn skips_server_while_breaker_is_open() {
+        let args = ["--min-players=5", "--circuit-breaker-failures=2"];
+        let mut h = Harness::new(&args, false);
+        assert!(h.update(None).await);
+        assert_eq!(h.state.breaker(), BreakerState::Closed);
+        assert!(h.update(None).await);
+        assert_eq!(h.state.breaker(), BreakerState::Open);
+        assert_eq!(h.status()["circuit_breaker"], "open");
+        assert!(!h.update(Some((5, 20))).await);
+        assert!(h.patches().is_empty());
+    }
+
+    #[tokio::test]
+    async fn closes_breaker_after_successful_probe() {
+        let mut h = Harness::new(&["--min-players=5"], false);
+        // Without a cooldown, the breaker is half-open as soon as it opens.
+        h.config.circuit_breaker = Some(CircuitBreaker {
+            failures: 1,
+            cooldown: Duration::ZERO,
+            max_cooldown: Duration::ZERO,
+        });
+        h.update(None).await;
+        assert_eq!(h.state.breaker(), BreakerState::HalfOpen);
+        // A failed probe opens it again.
+        assert!(h.update(None).await);
+        assert_eq!(h.state.breaker(), BreakerState::HalfOpen);
+        assert!(h.update(Some((5, 20))).await);
+        assert_eq!(h.state.breaker(), BreakerState::Closed);
+        assert_eq!(h.status()["circuit_breaker"], "closed");
+        assert_eq!(h.patches(), [true]);
+    }
+
+    #[tokio::test]
+    async fn only_relaxes_in_relax_only_direction() {
+        let mut h = Harness::new(&["--min-players=5", "--direction=relax_only"], false);
+        h.update(Some((5, 20))).await;
+        assert!(h.patches().is_empty());
+        assert!(!h.state.last_has_players);
+
+        let mut h = Harness::new(&["--min-players=5", "--direction=relax_only"], true);
+        h.update(Some((0, 20))).await;
+        assert_eq!(h.patches(), [false]);
+    }
+
+    #[tokio::test]
+    async fn only_protects_in_protect_only_direction() {
+        let mut h = Harness::new(&["--min-players=5", "--direction=protect_only"], true);
+        h.update(Some((0, 20))).await;
+        assert!(h.patches().is_empty());
+        assert!(h.state.last_has_players);
+
+        let mut h = Harness::new(&["--min-players=5", "--direction=protect_only"], false);
+        h.update(Some((5, 20))).await;
+        assert_eq!(h.patches(), [true]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_counts_no_patches() {
+        let mut h = Harness::new(&["--min-players=5", "--dry-run=true"], false);
+        h.update(Some((5, 20))).await;
+        // Targets only log what they would set in a dry run.
+        assert_eq!(h.patches(), [true]);
+        assert!(h.state.last_has_players);
+        assert_eq!(h.status()["last_has_players"], true);
+        assert_eq!(h.status()["last_patch"], serde_json::Value::Null);
+    }
+}
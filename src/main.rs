@@ -1,200 +1,181 @@
-use anyhow::{Context, Result, anyhow};
+use anyhow::Result;
 use futures::stream::StreamExt;
-use k8s_openapi::api::policy::v1::PodDisruptionBudget;
-use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
-use kube::{
-    Client,
-    api::{Api, Patch, PatchParams},
-};
-use percentage::Percentage;
-use signal_hook::consts::signal::{SIGINT, SIGTERM};
+use minecraft_pdb_mgr::config::Config;
+use minecraft_pdb_mgr::{Controller, UPDATE_SPAN, kube_client, telemetry};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
 use signal_hook_tokio::Signals;
-use std::sync::Arc;
-
-const DEFAULT_UPDATE_INTERVAL_SECONDS: u64 = 10;
-const DEFAULT_MIN_PLAYERS: u32 = 1;
+use std::sync::{Arc, RwLock};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter, filter_fn};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
 
 #[tokio::main]
 async fn main() {
-    unsafe {
-        std::env::set_var(
-            "RUST_LOG",
-            std::env::var("RUST_LOG").unwrap_or("info".to_string()),
-        );
+    // kube and reqwest share rustls, so they must agree on a crypto provider.
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("no crypto provider is installed yet");
+    // RUST_LOG takes precedence, for per-module levels.
+    let level = match std::env::var("LOG_LEVEL") {
+        Ok(level) => level.parse::<LevelFilter>().unwrap_or_else(|_| {
+            eprintln!("LOG_LEVEL must be one of: off, error, warn, info, debug, trace!");
+            std::process::exit(1);
+        }),
+        Err(_) => LevelFilter::INFO,
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+    let format = std::env::var("LOG_FORMAT").unwrap_or_default();
+    // Only the span of each server's update is logged, tagging every line
+    // within it with the server. The rest are only exported, so that the logs
+    // look the same either way.
+    let per_server = || filter_fn(|metadata| metadata.is_event() || metadata.name() == UPDATE_SPAN);
+    let logs = match format.as_str() {
+        "" | "text" => fmt::layer().with_filter(per_server()).boxed(),
+        "json" => fmt::layer().json().with_filter(per_server()).boxed(),
+        _ => {
+            eprintln!("LOG_FORMAT must be one of: text, json!");
+            std::process::exit(1);
+        }
+    };
+    let (spans, provider) = match telemetry::layer() {
+        Ok(Some((layer, provider))) => (Some(layer), Some(provider)),
+        Ok(None) => (None, None),
+        Err(e) => {
+            eprintln!("Failed to set up OpenTelemetry: {e}");
+            std::process::exit(1);
+        }
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(logs)
+        .with(spans)
+        .init();
+
+    let result = run().await;
+    if let Err(e) = &result {
+        tracing::error!("Error: {e}");
     }
-    env_logger::init();
-
-    if let Err(e) = run().await {
-        log::error!("Error: {e}");
+    if let Some(provider) = provider
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::warn!("Failed to flush spans: {e}");
+    }
+    if result.is_err() {
         std::process::exit(1);
     }
 }
 
-async fn handle_signals(shutdown_notify: Arc<tokio::sync::Notify>) {
-    let mut signals = Signals::new([SIGINT, SIGTERM]).unwrap();
+async fn handle_signals(
+    mut signals: Signals,
+    shutdown: CancellationToken,
+    config: Arc<RwLock<Config>>,
+    force_update: Arc<Notify>,
+) {
     while let Some(signal) = signals.next().await {
-        log::info!("Signal {signal} received, notifying shutdown.");
-        shutdown_notify.notify_waiters();
-    }
-}
-
-async fn get_server_player_info(host: &str, port: u16) -> Result<(u32, u32)> {
-    match mc_query::status::status_with_timeout(host, port, std::time::Duration::from_secs(10))
-        .await
-    {
-        Ok(s) => Ok((s.players.online, s.players.max)),
-        Err(e) => Err(e.into()),
+        if signal == SIGHUP {
+            reload_config(&config);
+            continue;
+        }
+        if signal == SIGUSR1 {
+            tracing::info!("Signal {signal} received, forcing an update.");
+            force_update.notify_one();
+            continue;
+        }
+        if signal == SIGUSR2 {
+            // Like a reload, this applies from the next update on.
+            let mut config = config.write().unwrap();
+            config.dry_run = !config.dry_run;
+            if config.dry_run {
+                tracing::warn!("Signal {signal} received, dry run enabled: PDBs won't be patched.");
+            } else {
+                tracing::warn!("Signal {signal} received, dry run disabled: patching PDBs again.");
+            }
+            continue;
+        }
+        tracing::info!("Signal {signal} received, notifying shutdown.");
+        shutdown.cancel();
     }
 }
 
-async fn try_update_pdb(
-    api: &Api<PodDisruptionBudget>,
-    pdb_name: &str,
-    min_players: &u32,
-    min_players_pct: &f64,
-    server_host: &str,
-    server_port: &u16,
-    last_has_players: &mut bool,
-) -> Result<()> {
-    let (players_online, players_max): (u32, u32) =
-        match get_server_player_info(server_host, *server_port).await {
-            Ok((online, max)) => (online, max),
-            Err(e) => {
-                return Err(anyhow!("Failed to get server player count: {e}"));
+fn reload_config(config: &RwLock<Config>) {
+    tracing::info!("Reloading configuration.");
+    match Config::from_env() {
+        Ok(new) => {
+            let mut config = config.write().unwrap();
+            tracing::info!("Old configuration: {:?}", *config);
+            tracing::info!("New configuration: {new:?}");
+            if new.health_port != config.health_port {
+                tracing::warn!("HEALTH_PORT cannot be changed without a restart.");
             }
-        };
-    let (players_needed, need_msg): (f64, String) = if *min_players_pct > 0.0 {
-        let req: f64 = Percentage::from_decimal(*min_players_pct).apply_to(players_max.into());
-        (
-            req,
-            format!("{:.0}% [{}]", *min_players_pct * 100.0, req as i32),
-        )
-    } else {
-        (f64::from(*min_players), format!("{min_players}"))
-    };
-    let has_players = f64::from(players_online) >= players_needed;
-
-    log::debug!(
-        "Condition {}: {players_online}/{players_max} players (need {need_msg}).",
-        if has_players { "met" } else { "unmet" }
-    );
-
-    if has_players == *last_has_players {
-        log::debug!("Server player state unchanged - skipping this update.");
-        return Ok(());
-    }
-
-    // Construct the patch.
-    let patch = Patch::Merge(serde_json::json!({
-        "spec": {
-            "maxUnavailable": u32::from(!has_players)
-        }
-    }));
-    // Patch it.
-    match api.patch(pdb_name, &PatchParams::default(), &patch).await {
-        Ok(_) => {
-            log::debug!("PodDisruptionBudget {pdb_name} patched successfully.");
-            *last_has_players = has_players;
-            Ok(())
+            if new.control_api != config.control_api {
+                tracing::warn!(
+                    "CONTROL_PORT and CONTROL_API_TOKEN cannot be changed without a restart."
+                );
+            }
+            if new.resolve_srv && !config.resolve_srv {
+                tracing::warn!("RESOLVE_SRV cannot be enabled without a restart.");
+            }
+            if new.drain_aware && !config.drain_aware {
+                tracing::warn!("DRAIN_AWARE cannot be enabled without a restart.");
+            }
+            if new.kubeconfig_path != config.kubeconfig_path
+                || new.kube_context != config.kube_context
+                || new.client_cert != config.client_cert
+            {
+                tracing::warn!(
+                    "KUBECONFIG_PATH, KUBE_CONTEXT, and the client certificate cannot be changed without a restart."
+                );
+            }
+            if new.leader_election != config.leader_election {
+                tracing::warn!("Leader election cannot be changed without a restart.");
+            }
+            if new.watch_pdbs != config.watch_pdbs
+                || (config.watch_pdbs && new.watch_namespaces != config.watch_namespaces)
+            {
+                tracing::warn!(
+                    "WATCH_PDBS and the namespaces it watches cannot be changed without a restart."
+                );
+            }
+            *config = new;
         }
-        Err(e) => Err(anyhow!(
-            "Failed to patch PodDisruptionBudget {pdb_name}: {e}"
-        )),
+        Err(e) => tracing::error!("Keeping the current configuration: {e}"),
     }
 }
 
 async fn run() -> Result<()> {
-    let shutdown_notify = Arc::new(tokio::sync::Notify::new());
-    tokio::spawn(handle_signals(shutdown_notify.clone()));
-
-    // Grab required values from env vars.
-    let update_interval: u64 = std::env::var("UPDATE_INTERVAL")
-        .unwrap_or(DEFAULT_UPDATE_INTERVAL_SECONDS.to_string())
-        .parse()
-        .context("UPDATE_INTERVAL conversion to u64 failed!")?;
-    let pod_namespace: String = std::env::var("POD_NAMESPACE")
-        .context("Could not determine pod namespace from POD_NAMESPACE!")?;
-    let pdb_name: String = std::env::var("PDB_NAME").context("No PDB_NAME specified!")?;
-    let min_players: u32 = match std::env::var("MIN_PLAYERS") {
-        Ok(s) => s.parse().context("MIN_PLAYERS conversion to u32 failed!")?,
-        Err(_) => DEFAULT_MIN_PLAYERS,
-    };
-    let min_players_pct: f64 = match std::env::var("MIN_PLAYERS_PERCENT") {
-        Ok(s) => s
-            .parse()
-            .context("MIN_PLAYERS_PERCENT conversion to f64 failed!")?,
-        Err(_) => 0.0,
-    };
-    let server_host: String = std::env::var("SERVER_HOST").context("No SERVER_HOST specified!")?;
-    let server_port: u16 = std::env::var("SERVER_PORT")
-        .context("No SERVER_PORT specified!")?
-        .parse()
-        .context("SERVER_PORT conversion to u16 failed!")?;
-
-    if std::env::var("RUST_LOG")?.to_lowercase() == "debug" {
-        if min_players_pct > 0.0 {
-            log::debug!(
-                "Will watch for minimum {:.0}% of players.",
-                min_players_pct * 100.0
-            );
-        } else {
-            log::debug!("Will watch for minimum {min_players} players.");
-        }
+    let config = Config::from_env()?;
+    if config.print_config {
+        println!("{}", serde_json::to_string_pretty(&config.to_json())?);
+        return Ok(());
     }
-
-    // Set up required Kube client.
-    let client = Client::try_default().await?;
-    let api: Api<PodDisruptionBudget> = Api::namespaced(client.clone(), &pod_namespace);
-    // Check the initial state of the PDB.
-    let pdb = api.get(&pdb_name).await;
-
-    // Save its current state if possible.
-    let mut last_has_players: bool = pdb.map_or_else(
-        |e| {
-            log::warn!("{e}");
-            false
-        },
-        |v| {
-            matches!(
-                v.spec.as_ref().and_then(|s| s.max_unavailable.as_ref()),
-                Some(IntOrString::Int(0))
-            )
-        },
-    );
-
-    // Wrap the update method in an error printer.
-    let mut do_update = async || {
-        if let Err(e) = try_update_pdb(
-            &api,
-            &pdb_name,
-            &min_players,
-            &min_players_pct,
-            &server_host,
-            &server_port,
-            &mut last_has_players,
-        )
-        .await
-        {
-            log::warn!("{e}");
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR1, SIGUSR2])?;
+    // Connecting may take a while with retries, and mustn't delay shutdown.
+    let terminated = async {
+        while let Some(signal) = signals.next().await {
+            if signal == SIGINT || signal == SIGTERM {
+                return signal;
+            }
         }
+        std::future::pending().await
     };
-    // Try initial update.
-    do_update().await;
-
-    // Now start running.
-    loop {
-        tokio::select! {
-            // Shut down if we received a signal.
-            _ = shutdown_notify.notified() => {
-                log::info!("Shutting down.");
-                break;
-            },
-            // The main loop.
-            _ = tokio::time::sleep(std::time::Duration::from_secs(update_interval)) => {
-                do_update().await;
-            }
+    let client = tokio::select! {
+        client = kube_client(&config) => client?,
+        signal = terminated => {
+            tracing::info!("Signal {signal} received while connecting, exiting.");
+            return Ok(());
         }
-    }
-
-    Ok(())
+    };
+    let controller = Controller::new(config, client);
+    let shutdown = CancellationToken::new();
+    tokio::spawn(handle_signals(
+        signals,
+        shutdown.clone(),
+        controller.config(),
+        controller.force_update(),
+    ));
+    controller.run(shutdown.cancelled_owned()).await
 }
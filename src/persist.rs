@@ -0,0 +1,167 @@
+//! Persistence of each server's state across restarts, in a JSON file.
+
+use crate::config::StateFile;
+use crate::state::ServerState;
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+/// The persisted part of each server's state: whether it has players, and its
+/// streak of query failures.
+pub type Snapshot = BTreeMap<String, (bool, u32)>;
+
+/// Take a snapshot of the states, to save or to tell whether they changed.
+pub fn snapshot(states: &HashMap<String, ServerState>) -> Snapshot {
+    states
+        .iter()
+        .map(|(name, s)| (name.clone(), (s.last_has_players, s.failures())))
+        .collect()
+}
+
+/// Saves snapshots to the state file when they change, and again before the
+/// file would be too old to be loaded, since an unchanged state is still
+/// current.
+pub struct Saver {
+    saved: Snapshot,
+    /// When the file was last written by this run.
+    saved_at: Option<Instant>,
+}
+
+impl Saver {
+    /// Start from the snapshot that was loaded, which is saved again on the
+    /// first update to refresh its age.
+    pub fn new(saved: Snapshot) -> Self {
+        Saver {
+            saved,
+            saved_at: None,
+        }
+    }
+
+    /// Save `snapshot` if it changed, or if the file is half its maximum age.
+    pub fn save(&mut self, file: &StateFile, snapshot: Snapshot) -> Result<()> {
+        let fresh = self
+            .saved_at
+            .is_some_and(|t| t.elapsed() < file.max_age / 2);
+        if snapshot == self.saved && fresh {
+            return Ok(());
+        }
+        save(file, &snapshot)?;
+        self.saved = snapshot;
+        self.saved_at = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// Read the saved states. Nothing is returned if the file doesn't exist or is
+/// older than its maximum age.
+pub fn load(file: &StateFile) -> Result<HashMap<String, ServerState>> {
+    let contents = match std::fs::read_to_string(&file.path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", file.path)),
+    };
+    let value: serde_json::Value = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", file.path))?;
+
+    let saved_at: DateTime<Utc> = value["saved_at"]
+        .as_str()
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(|| anyhow!("{} has no valid saved_at", file.path))?;
+    let age = (Utc::now() - saved_at).to_std().unwrap_or_default();
+    if age > file.max_age {
+        tracing::info!("Ignoring {}, saved {age:?} ago.", file.path);
+        return Ok(HashMap::new());
+    }
+
+    let mut states = HashMap::new();
+    for (name, server) in value["servers"].as_object().into_iter().flatten() {
+        let (Some(has_players), Some(failures)) = (
+            server["last_has_players"].as_bool(),
+            server["failures"].as_u64(),
+        ) else {
+            tracing::warn!(pdb_name = name, "Ignoring invalid state in {}.", file.path);
+            continue;
+        };
+        let failures = u32::try_from(failures).unwrap_or(u32::MAX);
+        states.insert(name.clone(), ServerState::restore(has_players, failures));
+    }
+    Ok(states)
+}
+
+/// Save a snapshot, replacing the file atomically so that a crash part-way
+/// through can't corrupt it.
+pub fn save(file: &StateFile, snapshot: &Snapshot) -> Result<()> {
+    let servers: serde_json::Map<String, serde_json::Value> = snapshot
+        .iter()
+        .map(|(name, (has_players, failures))| {
+            let value = serde_json::json!({
+                "last_has_players": has_players,
+                "failures": failures,
+            });
+            (name.clone(), value)
+        })
+        .collect();
+    let value = serde_json::json!({
+        "saved_at": Utc::now().to_rfc3339(),
+        "servers": servers,
+    });
+    let tmp = format!("{}.tmp", file.path);
+    std::fs::write(&tmp, value.to_string()).with_context(|| format!("Failed to write {tmp}"))?;
+    std::fs::rename(&tmp, &file.path).with_context(|| format!("Failed to write {}", file.path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn state_file(name: &str, max_age: Duration) -> StateFile {
+        let path = std::env::temp_dir().join(format!("{name}-{}.json", std::process::id()));
+        StateFile {
+            path: path.to_string_lossy().into_owned(),
+            max_age,
+        }
+    }
+
+    #[test]
+    fn round_trips_states() {
+        let file = state_file("round-trip", Duration::from_secs(600));
+        let saved = Snapshot::from([("survival".to_string(), (true, 2))]);
+        save(&file, &saved).unwrap();
+        let states = load(&file).unwrap();
+        std::fs::remove_file(&file.path).unwrap();
+        assert_eq!(snapshot(&states), saved);
+    }
+
+    #[test]
+    fn ignores_old_file() {
+        let file = state_file("old", Duration::from_millis(50));
+        save(
+            &file,
+            &Snapshot::from([("survival".to_string(), (true, 0))]),
+        )
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let states = load(&file).unwrap();
+        std::fs::remove_file(&file.path).unwrap();
+        assert!(states.is_empty());
+    }
+
+    #[test]
+    fn refreshes_unchanged_state() {
+        let file = state_file("refresh", Duration::from_millis(400));
+        let state = Snapshot::from([("survival".to_string(), (true, 0))]);
+        let mut saver = Saver::new(Snapshot::new());
+        // Updated every 150ms without a transition, for longer than the
+        // maximum age.
+        for _ in 0..4 {
+            saver.save(&file, state.clone()).unwrap();
+            std::thread::sleep(Duration::from_millis(150));
+        }
+        let states = load(&file).unwrap();
+        std::fs::remove_file(&file.path).unwrap();
+        assert_eq!(snapshot(&states), state);
+    }
+}
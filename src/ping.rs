@@ -0,0 +1,180 @@
+//! A minimal client for the Server List Ping over an already open connection,
+//! for those that `mc_query` can't make itself, such as through a proxy or
+//! with another protocol version.
+
+use anyhow::{Result, anyhow, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const HANDSHAKE: i32 = 0x00;
+const STATUS_REQUEST: i32 = 0x00;
+const STATUS_RESPONSE: i32 = 0x00;
+/// The "next state" of a handshake that asks for the status.
+const NEXT_STATE_STATUS: i32 = 1;
+/// Servers answer a status request whatever the protocol version.
+pub const DEFAULT_PROTOCOL_VERSION: i32 = -1;
+/// The longest status response read, far more than any real server sends, so
+/// that a response can't make the controller allocate gigabytes.
+const MAX_RESPONSE_LEN: usize = 1024 * 1024;
+
+/// Ask a server for its `(online, max, sample)` players, where `host` and
+/// `port` are the address the server is known by, announcing the given
+/// protocol version. The sample holds the names of some of the online players,
+/// if the server lists any.
+pub async fn status(
+    mut stream: TcpStream,
+    host: &str,
+    port: u16,
+    protocol_version: i32,
+) -> Result<(u32, u32, Option<Vec<String>>)> {
+    let mut handshake = Vec::new();
+    write_varint(&mut handshake, protocol_version);
+    write_varint(&mut handshake, i32::try_from(host.len())?);
+    handshake.extend_from_slice(host.as_bytes());
+    handshake.extend_from_slice(&port.to_be_bytes());
+    write_varint(&mut handshake, NEXT_STATE_STATUS);
+    stream.write_all(&packet(HANDSHAKE, &handshake)).await?;
+    stream.write_all(&packet(STATUS_REQUEST, &[])).await?;
+
+    let len = usize::try_from(read_varint(&mut stream).await?)?;
+    if len > MAX_RESPONSE_LEN {
+        bail!("Status response of {len} bytes is too long");
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    let mut body = body.as_slice();
+    if read_varint(&mut body).await? != STATUS_RESPONSE {
+        return Err(anyhow!("Invalid status response"));
+    }
+    let len = usize::try_from(read_varint(&mut body).await?)?;
+    let json = body
+        .get(..len)
+        .ok_or_else(|| anyhow!("Status response is longer than its packet"))?;
+
+    let value: serde_json::Value = serde_json::from_slice(json)?;
+    let field = |name: &str| {
+        value["players"][name]
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| anyhow!("Status response has no valid players.{name}"))
+    };
+    let sample = value["players"]["sample"].as_array().map(|sample| {
+        sample
+            .iter()
+            .filter_map(|player| player["name"].as_str().map(str::to_string))
+            .collect()
+    });
+    Ok((field("online")?, field("max")?, sample))
+}
+
+/// Frame a packet with its length and ID.
+pub fn packet(id: i32, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    write_varint(&mut body, id);
+    body.extend_from_slice(payload);
+    let mut packet = Vec::new();
+    write_varint(&mut packet, i32::try_from(body.len()).unwrap_or(i32::MAX));
+    packet.extend_from_slice(&body);
+    packet
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    // Negative values are written as their two's complement.
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+pub async fn read_varint(stream: &mut (impl AsyncRead + Unpin)) -> Result<i32> {
+    let mut value: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = stream.read_u8().await?;
+        value |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err(anyhow!("VarInt is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeSlp;
+
+    async fn ping(server: &FakeSlp) -> Result<(u32, u32, Option<Vec<String>>)> {
+        let stream = TcpStream::connect(("127.0.0.1", server.port)).await?;
+        status(stream, "mc.example.com", 25565, DEFAULT_PROTOCOL_VERSION).await
+    }
+
+    #[tokio::test]
+    async fn reads_players() {
+        let server = FakeSlp::players(3, 20).await;
+        assert_eq!(ping(&server).await.unwrap(), (3, 20, None));
+    }
+
+    #[tokio::test]
+    async fn reads_sample() {
+        let server = FakeSlp::start(
+            r#"{"players":{"online":2,"max":20,"sample":[
+                {"name":"Alex","id":"ec561538-f3fd-461d-aff5-086b22154bce"},
+                {"name":"Steve","id":"8667ba71-b85a-4004-af54-457a9734eed7"}]}}"#,
+        )
+        .await;
+        let (_, _, sample) = ping(&server).await.unwrap();
+        assert_eq!(sample.unwrap(), ["Alex", "Steve"]);
+    }
+
+    #[tokio::test]
+    async fn sends_address_in_handshake() {
+        let server = FakeSlp::players(0, 20).await;
+        ping(&server).await.unwrap();
+        let handshake = &server.handshakes()[0];
+        assert_eq!(handshake.host, "mc.example.com");
+        assert_eq!(handshake.port, 25565);
+        assert_eq!(handshake.next_state, NEXT_STATE_STATUS);
+    }
+
+    #[tokio::test]
+    async fn sends_protocol_version() {
+        let server = FakeSlp::players(0, 20).await;
+        // A single byte, several bytes, and a negative one as five.
+        for version in [47, 767, DEFAULT_PROTOCOL_VERSION] {
+            let stream = TcpStream::connect(("127.0.0.1", server.port))
+                .await
+                .unwrap();
+            status(stream, "mc.example.com", 25565, version)
+                .await
+                .unwrap();
+            assert_eq!(
+                server.handshakes().last().unwrap().protocol_version,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn writes_varints() {
+        let varint = |value| {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            buf
+        };
+        assert_eq!(varint(47), [0x2f]);
+        assert_eq!(varint(767), [0xff, 0x05]);
+        assert_eq!(varint(-1), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_players() {
+        let server = FakeSlp::start(r#"{"players":{"max":20}}"#).await;
+        let e = ping(&server).await.unwrap_err();
+        assert_eq!(e.to_string(), "Status response has no valid players.online");
+    }
+}
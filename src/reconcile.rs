@@ -0,0 +1,127 @@
+//! Re-applying the state of PDBs that were changed by something else, such as
+//! someone editing `maxUnavailable` by hand.
+
+use crate::config::{Config, WatchNamespaces, server_key};
+use crate::leader::LeaderElector;
+use crate::target::{self, Pdb, ProtectionTarget};
+use futures::stream::StreamExt;
+use k8s_openapi::api::policy::v1::PodDisruptionBudget;
+use kube::Client;
+use kube::api::Api;
+use kube::runtime::{WatchStreamExt, watcher};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+
+/// Whether each managed PDB should be protecting its server, by name.
+#[derive(Default)]
+pub struct Desired(Mutex<HashMap<String, bool>>);
+
+impl Desired {
+    pub fn set(&self, pdb_name: &str, protected: bool) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(pdb_name.to_string(), protected);
+    }
+
+    pub fn get(&self, pdb_name: &str) -> Option<bool> {
+        self.0.lock().unwrap().get(pdb_name).copied()
+    }
+
+    /// Forget the PDBs that are no longer managed.
+    pub fn retain(&self, mut keep: impl FnMut(&str) -> bool) {
+        self.0.lock().unwrap().retain(|name, _| keep(name));
+    }
+}
+
+/// The PDBs to watch, in the pod's own namespace and wherever they are
+/// discovered.
+pub fn watched_apis(client: &Client, config: &Config) -> Vec<Api<PodDisruptionBudget>> {
+    let mut namespaces = vec![config.pod_namespace.clone()];
+    match &config.watch_namespaces {
+        None => {}
+        Some(WatchNamespaces::All) => return vec![Api::all(client.clone())],
+        Some(WatchNamespaces::Only(only)) => {
+            namespaces.extend(
+                only.iter()
+                    .filter(|ns| **ns != config.pod_namespace)
+                    .cloned(),
+            );
+        }
+    }
+    namespaces
+        .iter()
+        .map(|namespace| Api::namespaced(client.clone(), namespace))
+        .collect()
+}
+
+/// Watch the PDBs, re-applying the desired state of any that drift from it,
+/// until shutdown.
+pub async fn watch(
+    api: Api<PodDisruptionBudget>,
+    config: Arc<RwLock<Config>>,
+    desired: Arc<Desired>,
+    elector: Option<Arc<LeaderElector>>,
+    shutdown: CancellationToken,
+) {
+    let stream = watcher(api.clone(), watcher::Config::default())
+        .default_backoff()
+        .applied_objects();
+    let mut stream = std::pin::pin!(stream);
+    loop {
+        let pdb = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            next = stream.next() => match next {
+                Some(Ok(pdb)) => pdb,
+                Some(Err(e)) => {
+                    tracing::warn!("Failed to watch PodDisruptionBudgets: {e}");
+                    continue;
+                }
+                None => return,
+            },
+        };
+        // Only the leader changes PDBs, and nothing is applied in a dry run.
+        if elector.as_ref().is_some_and(|e| !e.is_leader()) {
+            continue;
+        }
+        let (Some(name), Some(namespace)) = (&pdb.metadata.name, &pdb.metadata.namespace) else {
+            continue;
+        };
+        let own = config.read().unwrap().pod_namespace == *namespace;
+        let pdb_name = &server_key((!own).then_some(namespace.as_str()), name);
+        let Some(protected) = desired.get(pdb_name) else {
+            continue;
+        };
+        // A paused PDB may be changed by hand.
+        if pdb
+            .metadata
+            .annotations
+            .as_ref()
+            .is_some_and(target::is_paused)
+        {
+            continue;
+        }
+        let config = config.read().unwrap().clone();
+        if config.dry_run
+            || target::is_protected(
+                &pdb,
+                config.pdb_field,
+                &config.max_unavailable,
+                &config.min_available,
+            ) == protected
+        {
+            continue;
+        }
+
+        let target = Pdb::new(api.clone().into_client(), namespace, &config, name);
+        tracing::warn!(
+            pdb_name,
+            "PodDisruptionBudget was changed externally, re-applying {}.",
+            target.setting(protected)
+        );
+        if let Err(e) = target.set_protected(protected).await {
+            tracing::warn!(pdb_name, "{e}");
+        }
+    }
+}
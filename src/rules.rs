@@ -0,0 +1,258 @@
+//! Rules that decide whether a server is protected from its players and the
+//! time of week, in place of a threshold. A server is protected while any
+//! rule matches.
+
+use crate::schedule;
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+
+#[derive(Clone, Debug)]
+pub struct ProtectionRules {
+    pub rules: Vec<ProtectionRule>,
+    /// The timezone the times of day are evaluated in.
+    pub timezone: Tz,
+}
+
+/// The conditions of a rule, all of which must hold for it to match.
+#[derive(Clone, Debug)]
+pub struct ProtectionRule {
+    /// The least online players.
+    pub min_players: Option<u32>,
+    /// The least fraction of the max players online. Never met by a server
+    /// that reports a max of 0.
+    pub min_percent: Option<f64>,
+    /// The least max players the server must report, e.g. to only apply the
+    /// rule to a large server.
+    pub min_max_players: Option<u32>,
+    /// The days the rule applies on, or every day if empty.
+    pub days: Vec<Weekday>,
+    /// The times of day the rule applies between, or all day if unset.
+    pub hours: Option<(NaiveTime, NaiveTime)>,
+}
+
+impl ProtectionRules {
+    /// The fewest online players that match a rule at `now` for a server with
+    /// `max` players, and which rule that is. Infinite if no rule applies.
+    pub fn players_needed(&self, max: u32, now: DateTime<Utc>) -> (f64, String) {
+        let now = now.with_timezone(&self.timezone);
+        let (time, today) = (now.time(), now.weekday());
+        self.rules
+            .iter()
+            .enumerate()
+            .filter_map(|(i, rule)| Some((rule.players_needed(max, time, today)?, i)))
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map_or_else(
+                || (f64::INFINITY, "no rule applies".to_string()),
+                |(needed, i)| (needed, format!("{needed:.0} by rule {i}")),
+            )
+    }
+}
+
+impl ProtectionRule {
+    /// The fewest online players that match the rule, unless it doesn't apply
+    /// at all.
+    fn players_needed(&self, max: u32, time: NaiveTime, today: Weekday) -> Option<f64> {
+        if self.min_max_players.is_some_and(|n| max < n) {
+            return None;
+        }
+        let applies = match self.hours {
+            Some((start, end)) => schedule::covers(&self.days, start, end, time, today),
+            None => self.days.is_empty() || self.days.contains(&today),
+        };
+        if !applies {
+            return None;
+        }
+        // Any percentage of zero is zero, which every server would meet.
+        let percent = match self.min_percent {
+            Some(_) if max == 0 => return None,
+            Some(pct) => pct * f64::from(max),
+            None => 0.0,
+        };
+        Some(f64::from(self.min_players.unwrap_or_default()).max(percent))
+    }
+}
+
+/// Parse rules from a JSON array such as
+/// `[{"min_players": 10}, {"start": "18:00", "end": "23:00", "min_players": 3}]`.
+pub fn parse_rules(value: &str) -> Result<Vec<ProtectionRule>, String> {
+    let value: serde_json::Value = serde_json::from_str(value).map_err(|e| e.to_string())?;
+    let rules = value.as_array().ok_or("is not a JSON array")?;
+    rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| {
+            let count = |key: &str| match &rule[key] {
+                serde_json::Value::Null => Ok(None),
+                n => n
+                    .as_u64()
+                    .and_then(|n| u32::try_from(n).ok())
+                    .map(Some)
+                    .ok_or_else(|| format!("rule {i} has an invalid {key}")),
+            };
+            let min_players = count("min_players")?;
+            let min_max_players = count("min_max_players")?;
+            let min_percent = match &rule["min_percent"] {
+                serde_json::Value::Null => None,
+                pct => Some(
+                    pct.as_f64()
+                        .filter(|pct| *pct > 0.0 && *pct <= 1.0)
+                        .ok_or_else(|| format!("rule {i} has an invalid min_percent"))?,
+                ),
+            };
+            if min_players.is_none() && min_percent.is_none() {
+                return Err(format!("rule {i} needs min_players or min_percent"));
+            }
+            let days = schedule::parse_days(&rule["days"])
+                .ok_or_else(|| format!("rule {i} has invalid days"))?;
+            let hours = match (&rule["start"], &rule["end"]) {
+                (serde_json::Value::Null, serde_json::Value::Null) => None,
+                (start, end) => Some(
+                    schedule::parse_time(start)
+                        .zip(schedule::parse_time(end))
+                        .ok_or_else(|| {
+                            format!("rule {i} needs both a valid start and end (HH:MM)")
+                        })?,
+                ),
+            };
+            Ok(ProtectionRule {
+                min_players,
+                min_percent,
+                min_max_players,
+                days,
+                hours,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rules evaluated in a timezone two hours ahead of UTC in July.
+    fn in_berlin(json: &str) -> ProtectionRules {
+        ProtectionRules {
+            rules: parse_rules(json).unwrap(),
+            timezone: chrono_tz::Europe::Berlin,
+        }
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    const FRIDAY: &str = "2026-07-03T12:00:00Z";
+
+    #[test]
+    fn needs_fewest_players_of_any_rule() {
+        let rules = in_berlin(r#"[{"min_players": 10}, {"min_percent": 0.25}]"#);
+        assert_eq!(
+            rules.players_needed(20, at(FRIDAY)),
+            (5.0, "5 by rule 1".to_string())
+        );
+        assert_eq!(
+            rules.players_needed(100, at(FRIDAY)),
+            (10.0, "10 by rule 0".to_string())
+        );
+    }
+
+    #[test]
+    fn needs_every_condition_of_a_rule() {
+        let rules = in_berlin(r#"[{"min_players": 3, "min_percent": 0.5}]"#);
+        assert_eq!(rules.players_needed(20, at(FRIDAY)).0, 10.0);
+        assert_eq!(rules.players_needed(4, at(FRIDAY)).0, 3.0);
+    }
+
+    #[test]
+    fn applies_on_days_in_timezone() {
+        let rules = in_berlin(r#"[{"days": ["sat"], "min_players": 2}]"#);
+        // 01:30 on Saturday in Berlin, still Friday in UTC.
+        assert_eq!(rules.players_needed(20, at("2026-07-03T23:30:00Z")).0, 2.0);
+        assert_eq!(
+            rules.players_needed(20, at("2026-07-03T21:00:00Z")).0,
+            f64::INFINITY
+        );
+    }
+
+    #[test]
+    fn applies_between_hours_in_timezone() {
+        let rules = in_berlin(r#"[{"start": "18:00", "end": "23:00", "min_players": 3}]"#);
+        // 18:30 in Berlin, but 16:30 in UTC.
+        assert_eq!(rules.players_needed(20, at("2026-07-03T16:30:00Z")).0, 3.0);
+        assert_eq!(
+            rules.players_needed(20, at("2026-07-03T21:30:00Z")).0,
+            f64::INFINITY
+        );
+    }
+
+    #[test]
+    fn applies_past_midnight_on_day_window_started() {
+        let rules =
+            in_berlin(r#"[{"days": ["fri"], "start": "22:00", "end": "02:00", "min_players": 3}]"#);
+        let needed = |time| rules.players_needed(20, at(time)).0;
+        // 22:30 on Friday in Berlin.
+        assert_eq!(needed("2026-07-03T20:30:00Z"), 3.0);
+        // 00:30 on Saturday, in the window that started on Friday.
+        assert_eq!(needed("2026-07-03T22:30:00Z"), 3.0);
+        // 02:30 on Saturday, after it ended.
+        assert_eq!(needed("2026-07-04T00:30:00Z"), f64::INFINITY);
+        // 00:30 on Sunday, in a window that started on Saturday.
+        assert_eq!(needed("2026-07-04T22:30:00Z"), f64::INFINITY);
+    }
+
+    #[test]
+    fn applies_only_to_servers_large_enough() {
+        let rules = in_berlin(r#"[{"min_players": 5, "min_max_players": 50}]"#);
+        assert_eq!(rules.players_needed(49, at(FRIDAY)).0, f64::INFINITY);
+        assert_eq!(rules.players_needed(50, at(FRIDAY)).0, 5.0);
+    }
+
+    #[test]
+    fn percent_never_met_without_max() {
+        let rules = in_berlin(r#"[{"min_percent": 0.5}]"#);
+        assert_eq!(
+            rules.players_needed(0, at(FRIDAY)),
+            (f64::INFINITY, "no rule applies".to_string())
+        );
+        let rules = in_berlin(r#"[{"min_percent": 0.5}, {"min_players": 4}]"#);
+        assert_eq!(rules.players_needed(0, at(FRIDAY)).0, 4.0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (json, error) in [
+            (r#"{"min_players": 1}"#, "is not a JSON array"),
+            (
+                r#"[{"min_players": -1}]"#,
+                "rule 0 has an invalid min_players",
+            ),
+            (
+                r#"[{"min_players": 1, "min_max_players": "big"}]"#,
+                "rule 0 has an invalid min_max_players",
+            ),
+            (
+                r#"[{"min_players": 1}, {"min_percent": 1.5}]"#,
+                "rule 1 has an invalid min_percent",
+            ),
+            (
+                r#"[{"days": ["sat"]}]"#,
+                "rule 0 needs min_players or min_percent",
+            ),
+            (
+                r#"[{"min_players": 1, "days": ["someday"]}]"#,
+                "rule 0 has invalid days",
+            ),
+            (
+                r#"[{"min_players": 1, "start": "18:00"}]"#,
+                "rule 0 needs both a valid start and end (HH:MM)",
+            ),
+            (
+                r#"[{"min_players": 1, "start": "25:00", "end": "02:00"}]"#,
+                "rule 0 needs both a valid start and end (HH:MM)",
+            ),
+        ] {
+            assert_eq!(parse_rules(json).unwrap_err(), error, "{json}");
+        }
+        assert!(parse_rules("[").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::time::Duration;
+
+/// A recurring window of time during which disruption is always allowed.
+#[derive(Clone, Debug)]
+pub struct MaintenanceWindow {
+    /// When each window starts.
+    pub schedule: Schedule,
+    /// How long each window lasts.
+    pub duration: Duration,
+    /// How long disruption stays allowed after each window ends.
+    pub cooldown: Duration,
+    /// The timezone `schedule` is evaluated in.
+    pub timezone: Tz,
+}
+
+impl MaintenanceWindow {
+    /// Whether `now` falls inside a window, i.e. a window started no more
+    /// than `duration` ago.
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let Ok(duration) = chrono::Duration::from_std(self.duration) else {
+            return false;
+        };
+        let now = now.with_timezone(&self.timezone);
+        self.schedule
+            .after(&(now - duration))
+            .next()
+            .is_some_and(|start| start <= now)
+    }
+}
+
+/// Minimum player counts that apply instead of each server's threshold at
+/// certain times of the week.
+#[derive(Clone, Debug)]
+pub struct ThresholdSchedule {
+    /// The rules, of which the first that covers the current time applies.
+    pub rules: Vec<ThresholdRule>,
+    /// The timezone the rules are evaluated in.
+    pub timezone: Tz,
+}
+
+/// A minimum player count that applies between two times of day.
+#[derive(Clone, Debug)]
+pub struct ThresholdRule {
+    /// The days the rule applies on, or every day if empty. A range that
+    /// crosses midnight belongs to the day it starts on.
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub min_players: u32,
+}
+
+impl ThresholdSchedule {
+    /// The minimum player count of the rule that covers `now`, if any, and
+    /// which rule that is.
+    pub fn active(&self, now: DateTime<Utc>) -> Option<(usize, u32)> {
+        let now = now.with_timezone(&self.timezone);
+        let (time, today) = (now.time(), now.weekday());
+        self.rules
+            .iter()
+            .position(|rule| covers(&rule.days, rule.start, rule.end, time, today))
+            .map(|i| (i, self.rules[i].min_players))
+    }
+}
+
+/// Whether `time` on `today` falls between `start` and `end` on one of
+/// `days`, or on any day if empty. A range that crosses midnight belongs to
+/// the day it starts on.
+pub fn covers(
+    days: &[Weekday],
+    start: NaiveTime,
+    end: NaiveTime,
+    time: NaiveTime,
+    today: Weekday,
+) -> bool {
+    let day = if start <= end {
+        (start <= time && time < end).then_some(today)
+    } else if time >= start {
+        Some(today)
+    } else {
+        (time < end).then(|| today.pred())
+    };
+    day.is_some_and(|day| days.is_empty() || days.contains(&day))
+}
+
+/// Parse threshold rules from a JSON array such as
+/// `[{"days": ["sat", "sun"], "start": "18:00", "end": "23:00", "min_players": 5}]`.
+pub fn parse_threshold_rules(value: &str) -> Result<Vec<ThresholdRule>, String> {
+    let value: serde_json::Value = serde_json::from_str(value).map_err(|e| e.to_string())?;
+    let rules = value.as_array().ok_or("is not a JSON array")?;
+    rules
+        .iter()
+        .enumerate()
+        .map(|(i, rule)| {
+            let time = |key: &str| {
+                parse_time(&rule[key]).ok_or_else(|| format!("rule {i} has no valid {key} (HH:MM)"))
+            };
+            let days =
+                parse_days(&rule["days"]).ok_or_else(|| format!("rule {i} has invalid days"))?;
+            let min_players = rule["min_players"]
+                .as_u64()
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| format!("rule {i} has no valid min_players"))?;
+            Ok(ThresholdRule {
+                days,
+                start: time("start")?,
+                end: time("end")?,
+                min_players,
+            })
+        })
+        .collect()
+}
+
+/// Parse a time of day as `HH:MM`.
+pub fn parse_time(value: &serde_json::Value) -> Option<NaiveTime> {
+    value
+        .as_str()
+        .and_then(|t| NaiveTime::parse_from_str(t, "%H:%M").ok())
+}
+
+/// Parse a list of days such as `["sat", "sun"]`, which is empty if unset.
+pub fn parse_days(value: &serde_json::Value) -> Option<Vec<Weekday>> {
+    match value {
+        serde_json::Value::Null => Some(Vec::new()),
+        serde_json::Value::Array(days) => days
+            .iter()
+            .map(|d| d.as_str().and_then(|d| d.parse().ok()))
+            .collect(),
+        _ => None,
+    }
+}
@@ -0,0 +1,679 @@
+//! The sources a server's player count can be read from. Each one implements
+//! [`PlayerSource`], and [`for_server`] picks the one that the configuration
+//! asks for.
+
+use crate::bedrock;
+use crate::config::{
+    AggregateFailureMode, Config, PlayerSourceKind, QueryProtocol, Secret, ServerConfig,
+    ServerEdition, StartingMode,
+};
+use crate::legacy;
+use crate::ping;
+use crate::proxy::{self, QueryProxy};
+use crate::srv::SrvResolver;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The players of a server.
+#[derive(Debug)]
+pub struct PlayerInfo {
+    pub online: u32,
+    pub max: u32,
+    /// The names of the online players, if the source provides them. The
+    /// status ping only lists a sample of them, so a player filter can't be
+    /// used with it.
+    pub names: Option<Vec<String>>,
+}
+
+impl PlayerInfo {
+    pub fn counts(online: u32, max: u32) -> Self {
+        PlayerInfo {
+            online,
+            max,
+            names: None,
+        }
+    }
+}
+
+/// The error of a status ping to a server that already accepts connections,
+/// as it does while it is still starting up.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct Starting(String);
+
+/// Somewhere the players of a server can be counted.
+#[async_trait]
+pub trait PlayerSource: Send + Sync {
+    /// Count the players that are online right now.
+    async fn player_info(&self) -> Result<PlayerInfo>;
+}
+
+/// The Server List Ping that every Java server answers.
+pub struct Status {
+    host: String,
+    port: u16,
+    timeout: Duration,
+    /// Follows the server's SRV record before pinging, if set.
+    srv: Option<Arc<SrvResolver>>,
+    /// The proxy to connect through, if any.
+    proxy: Option<QueryProxy>,
+    /// The protocol version sent in the handshake, if not the default.
+    protocol_version: Option<i32>,
+    /// Whether to tell a server that is starting apart from one that is down.
+    probe_starting: bool,
+}
+
+#[async_trait]
+impl PlayerSource for Status {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        let (host, port) = match &self.srv {
+            Some(srv) => srv.resolve(&self.host, self.port).await,
+            None => (self.host.clone(), self.port),
+        };
+        let result = self.ping(&host, port).await;
+        if !self.probe_starting {
+            return result;
+        }
+        probe_starting(result, &host, port, self.timeout, self.proxy.as_ref()).await
+    }
+}
+
+impl Status {
+    async fn ping(&self, host: &str, port: u16) -> Result<PlayerInfo> {
+        // mc_query can only make its own connection, with the default
+        // protocol version.
+        if self.proxy.is_some() || self.protocol_version.is_some() {
+            let version = self
+                .protocol_version
+                .unwrap_or(ping::DEFAULT_PROTOCOL_VERSION);
+            let ping = async {
+                let stream = proxy::connect(self.proxy.as_ref(), host, port).await?;
+                ping::status(stream, host, port, version).await
+            };
+            let (online, max, names) = tokio::time::timeout(self.timeout, ping)
+                .await
+                .map_err(|_| anyhow!("Status query timed out"))??;
+            return Ok(PlayerInfo { online, max, names });
+        }
+        let s = mc_query::status::status_with_timeout(&bracketed(host), port, self.timeout).await?;
+        Ok(PlayerInfo {
+            online: s.players.online,
+            max: s.players.max,
+            names: s
+                .players
+                .sample
+                .map(|sample| sample.into_iter().map(|p| p.name).collect()),
+        })
+    }
+}
+
+/// The GameSpy4 query protocol, which must be enabled on the server.
+pub struct Query {
+    host: String,
+    port: u16,
+    timeout: Duration,
+    /// Whether to request the full stat, which is the only one that includes
+    /// player names.
+    full: bool,
+}
+
+#[async_trait]
+impl PlayerSource for Query {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        let (host, port, timeout) = (&bracketed(&self.host), self.port, self.timeout);
+        if self.full {
+            let s = mc_query::query::stat_full_with_timeout(host, port, timeout).await?;
+            return Ok(PlayerInfo {
+                online: s.num_players.try_into()?,
+                max: s.max_players.try_into()?,
+                names: Some(s.players),
+            });
+        }
+        let s = mc_query::query::stat_basic_with_timeout(host, port, timeout).await?;
+        Ok(PlayerInfo::counts(
+            s.num_players.try_into()?,
+            s.max_players.try_into()?,
+        ))
+    }
+}
+
+/// The output of the `list` command, run over RCON.
+pub struct Rcon {
+    host: String,
+    port: u16,
+    password: Secret,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl PlayerSource for Rcon {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        tokio::time::timeout(self.timeout, self.list())
+            .await
+            .map_err(|_| anyhow!("RCON timed out"))?
+    }
+}
+
+impl Rcon {
+    async fn list(&self) -> Result<PlayerInfo> {
+        let mut client = mc_query::rcon::RconClient::new(&bracketed(&self.host), self.port).await?;
+        client.authenticate(&self.password.0).await?;
+        let response = client.run_command("list").await?;
+        // The connection is no longer needed either way.
+        let _ = client.disconnect().await;
+        parse_list_response(&response)
+    }
+}
+
+/// The Server List Ping of Java servers from before 1.7.
+pub struct Legacy {
+    host: String,
+    port: u16,
+    timeout: Duration,
+    /// Follows the server's SRV record before pinging, if set.
+    srv: Option<Arc<SrvResolver>>,
+    /// The proxy to connect through, if any.
+    proxy: Option<QueryProxy>,
+    /// Whether to tell a server that is starting apart from one that is down.
+    probe_starting: bool,
+}
+
+#[async_trait]
+impl PlayerSource for Legacy {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        let (host, port) = match &self.srv {
+            Some(srv) => srv.resolve(&self.host, self.port).await,
+            None => (self.host.clone(), self.port),
+        };
+        let result = legacy::ping(&host, port, self.timeout, self.proxy.as_ref())
+            .await
+            .map(|(online, max)| PlayerInfo::counts(online, max));
+        if !self.probe_starting {
+            return result;
+        }
+        probe_starting(result, &host, port, self.timeout, self.proxy.as_ref()).await
+    }
+}
+
+/// Turn a failed ping into [`Starting`] if the server still accepts
+/// connections, since one that is down doesn't.
+async fn probe_starting(
+    result: Result<PlayerInfo>,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    proxy: Option<&QueryProxy>,
+) -> Result<PlayerInfo> {
+    let Err(e) = result else {
+        return result;
+    };
+    match tokio::time::timeout(timeout, proxy::connect(proxy, host, port)).await {
+        Ok(Ok(_)) => Err(Starting(e.to_string()).into()),
+        _ => Err(e),
+    }
+}
+
+/// The RakNet ping that Bedrock servers answer.
+pub struct Bedrock {
+    host: String,
+    port: u16,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl PlayerSource for Bedrock {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        let (online, max) = bedrock::ping(&self.host, self.port, self.timeout).await?;
+        Ok(PlayerInfo::counts(online, max))
+    }
+}
+
+/// A JSON endpoint, such as one served by a server plugin.
+pub struct Http {
+    client: reqwest::Client,
+    url: String,
+    online_field: String,
+    max_field: String,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl PlayerSource for Http {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        let value: serde_json::Value = self
+            .client
+            .get(&self.url)
+            .timeout(self.timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let field = |path: &str| {
+            path.split('.')
+                .try_fold(&value, |value, key| value.get(key))
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| anyhow!("{} has no valid {path} field", self.url))
+        };
+        Ok(PlayerInfo::counts(
+            field(&self.online_field)?,
+            field(&self.max_field)?,
+        ))
+    }
+}
+
+/// A metric already scraped by Prometheus, read with instant queries.
+pub struct Prometheus {
+    client: reqwest::Client,
+    /// The instant query endpoint.
+    url: String,
+    query: String,
+    max_query: Option<String>,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl PlayerSource for Prometheus {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        let online = self.instant(&self.query).await?;
+        // Without a max, only absolute thresholds can be used, as with a
+        // server that reports a max of 0.
+        let max = match &self.max_query {
+            Some(query) => self.instant(query).await?,
+            None => 0,
+        };
+        Ok(PlayerInfo::counts(online, max))
+    }
+}
+
+impl Prometheus {
+    /// Run an instant query that returns a single number.
+    async fn instant(&self, query: &str) -> Result<u32> {
+        let url = url::Url::parse_with_params(&self.url, [("query", query)])?;
+        let response = self.client.get(url).timeout(self.timeout).send().await?;
+        let status = response.status();
+        // Failed queries are explained in the body, if there is one.
+        let Ok(value) = response.json::<serde_json::Value>().await else {
+            return Err(anyhow!("Prometheus responded with {status}"));
+        };
+        if value["status"] != "success" {
+            let error = value["error"].as_str().unwrap_or(status.as_str());
+            return Err(anyhow!("Prometheus query {query} failed: {error}"));
+        }
+
+        // A sample is a timestamp and its value as a string.
+        let data = &value["data"];
+        let sample = match (data["resultType"].as_str(), data["result"].as_array()) {
+            (Some("scalar"), _) => &data["result"],
+            (Some("vector"), Some(series)) => match series.as_slice() {
+                [] => return Err(anyhow!("Prometheus query {query} returned no data")),
+                [series] => &series["value"],
+                series => {
+                    return Err(anyhow!(
+                        "Prometheus query {query} returned {} series instead of one",
+                        series.len()
+                    ));
+                }
+            },
+            _ => return Err(anyhow!("Prometheus query {query} didn't return a vector")),
+        };
+        sample[1]
+            .as_str()
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|v| (0.0..=f64::from(u32::MAX)).contains(v))
+            .map(|v| v.round() as u32)
+            .ok_or_else(|| anyhow!("Prometheus query {query} returned an invalid count"))
+    }
+}
+
+/// The sum of several backends, such as those behind a proxy.
+pub struct Aggregate {
+    pdb_name: String,
+    /// Each backend, by its `host:port`.
+    backends: Vec<(String, Box<dyn PlayerSource>)>,
+    failure_mode: AggregateFailureMode,
+}
+
+#[async_trait]
+impl PlayerSource for Aggregate {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        // Every backend is queried concurrently and their players summed.
+        let results =
+            futures::future::join_all(self.backends.iter().map(|(_, b)| b.player_info())).await;
+        let mut total: Option<PlayerInfo> = None;
+        let mut counts = Vec::with_capacity(self.backends.len());
+        for ((backend, _), result) in self.backends.iter().zip(results) {
+            let info = match (result, self.failure_mode) {
+                (Ok(info), _) => {
+                    counts.push(format!("{backend}={}/{}", info.online, info.max));
+                    info
+                }
+                (Err(e), AggregateFailureMode::Fail) => {
+                    return Err(anyhow!("backend {backend}: {e}"));
+                }
+                (Err(e), AggregateFailureMode::Skip) => {
+                    tracing::warn!(pdb_name = self.pdb_name, "Ignoring backend {backend}: {e}");
+                    counts.push(format!("{backend}=skipped"));
+                    continue;
+                }
+                (Err(e), AggregateFailureMode::Zero) => {
+                    tracing::warn!(
+                        pdb_name = self.pdb_name,
+                        "Counting backend {backend} as empty: {e}"
+                    );
+                    counts.push(format!("{backend}=0/0 (down)"));
+                    PlayerInfo {
+                        online: 0,
+                        max: 0,
+                        names: Some(Vec::new()),
+                    }
+                }
+            };
+            total = Some(match total {
+                Some(total) => PlayerInfo {
+                    // Backends can report anything, which mustn't overflow.
+                    online: total.online.saturating_add(info.online),
+                    max: total.max.saturating_add(info.max),
+                    names: total.names.zip(info.names).map(|(mut a, b)| {
+                        a.extend(b);
+                        a
+                    }),
+                },
+                None => info,
+            });
+        }
+        tracing::debug!(
+            pdb_name = self.pdb_name,
+            "Backend players: {}.",
+            counts.join(", ")
+        );
+        // Treating every backend being down as empty would allow disruption,
+        // unless that is what was asked for.
+        total.ok_or_else(|| anyhow!("no backend responded"))
+    }
+}
+
+/// The same server on several ports, of which the first to respond counts.
+pub struct Candidates {
+    pdb_name: String,
+    /// Each candidate, by its port, in the order they are tried.
+    ports: Vec<(u16, Box<dyn PlayerSource>)>,
+}
+
+#[async_trait]
+impl PlayerSource for Candidates {
+    async fn player_info(&self) -> Result<PlayerInfo> {
+        let mut errors = Vec::with_capacity(self.ports.len());
+        let mut starting = None;
+        for (port, source) in &self.ports {
+            match source.player_info().await {
+                Ok(info) => {
+                    tracing::debug!(pdb_name = self.pdb_name, "Port {port} responded.");
+                    return Ok(info);
+                }
+                Err(e) => {
+                    errors.push(format!("port {port}: {e}"));
+                    // A port that is still starting is where the server will be.
+                    if starting.is_none() && e.is::<Starting>() {
+                        starting = Some(e);
+                    }
+                }
+            }
+        }
+        Err(starting.unwrap_or_else(|| anyhow!("no port responded ({})", errors.join(", "))))
+    }
+}
+
+/// Choose where to count a server's players from, based on the configuration.
+pub fn for_server(
+    config: &Config,
+    server: &ServerConfig,
+    srv: Option<&Arc<SrvResolver>>,
+    http: &reqwest::Client,
+) -> Box<dyn PlayerSource> {
+    if server.aggregate_hosts.is_empty() && server.fallback_ports.is_empty() {
+        return for_host(
+            config,
+            &server.server_host,
+            server.server_port,
+            server.query_port,
+            srv,
+            http,
+        );
+    }
+    if server.aggregate_hosts.is_empty() {
+        // Each port is queried on itself, unless QUERY_PORT was set apart.
+        let query_port = |port| {
+            if server.query_port == server.server_port {
+                port
+            } else {
+                server.query_port
+            }
+        };
+        return Box::new(Candidates {
+            pdb_name: server.pdb_name.clone(),
+            ports: std::iter::once(server.server_port)
+                .chain(server.fallback_ports.iter().copied())
+                .map(|port| {
+                    let source = for_host(
+                        config,
+                        &server.server_host,
+                        port,
+                        query_port(port),
+                        srv,
+                        http,
+                    );
+                    (port, source)
+                })
+                .collect(),
+        });
+    }
+    Box::new(Aggregate {
+        pdb_name: server.pdb_name.clone(),
+        backends: server
+            .aggregate_hosts
+            .iter()
+            .map(|(host, port)| {
+                let source = for_host(config, host, *port, *port, srv, http);
+                (format!("{host}:{port}"), source)
+            })
+            .collect(),
+        failure_mode: config.aggregate_failure_mode,
+    })
+}
+
+fn for_host(
+    config: &Config,
+    host: &str,
+    port: u16,
+    query_port: u16,
+    srv: Option<&Arc<SrvResolver>>,
+    http: &reqwest::Client,
+) -> Box<dyn PlayerSource> {
+    let (host, timeout) = (host.to_string(), config.query_timeout);
+    // The endpoint reports the count the same way for either edition.
+    if let Some(players) = config
+        .http_players
+        .as_ref()
+        .filter(|_| config.player_source == PlayerSourceKind::Http)
+    {
+        return Box::new(Http {
+            client: http.clone(),
+            url: players.url.replace("{host}", &bracketed(&host)),
+            online_field: players.online_field.clone(),
+            max_field: players.max_field.clone(),
+            timeout,
+        });
+    }
+    if let Some(players) = config
+        .prometheus_players
+        .as_ref()
+        .filter(|_| config.player_source == PlayerSourceKind::Prometheus)
+    {
+        return Box::new(Prometheus {
+            client: http.clone(),
+            url: format!("{}/api/v1/query", players.url.trim_end_matches('/')),
+            query: players.query.replace("{host}", &host),
+            max_query: players
+                .max_query
+                .as_ref()
+                .map(|query| query.replace("{host}", &host)),
+            timeout,
+        });
+    }
+    if config.server_edition == ServerEdition::Bedrock {
+        return Box::new(Bedrock {
+            host,
+            port,
+            timeout,
+        });
+    }
+    if config.player_source == PlayerSourceKind::Rcon {
+        return Box::new(Rcon {
+            host,
+            port: config.rcon_port,
+            password: config.rcon_password.clone(),
+            timeout,
+        });
+    }
+    match config.query_protocol {
+        QueryProtocol::Status => Box::new(Status {
+            host,
+            port,
+            timeout,
+            srv: srv.filter(|_| config.resolve_srv).cloned(),
+            proxy: config.query_proxy.clone(),
+            protocol_version: config.handshake_protocol_version,
+            probe_starting: config.starting_mode == StartingMode::Protect,
+        }),
+        QueryProtocol::Query => Box::new(Query {
+            host,
+            port: query_port,
+            timeout,
+            full: config.player_filter.is_some() || config.log_player_names,
+        }),
+        QueryProtocol::Legacy => Box::new(Legacy {
+            host,
+            port,
+            timeout,
+            srv: srv.filter(|_| config.resolve_srv).cloned(),
+            proxy: config.query_proxy.clone(),
+            probe_starting: config.starting_mode == StartingMode::Protect,
+        }),
+    }
+}
+
+/// Wrap an IPv6 address in brackets, for the APIs that append `:port` to the
+/// host themselves.
+fn bracketed(host: &str) -> String {
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    }
+}
+
+/// Parse the output of the `list` command, which is either "There are N of a
+/// max of M players online: ..." or, before 1.13, "There are N/M players
+/// online: ...", followed by a comma-separated list of names.
+fn parse_list_response(response: &str) -> Result<PlayerInfo> {
+    let err = || anyhow!("Unexpected RCON list response: {response}");
+    let (_, rest) = response.split_once("There are ").ok_or_else(err)?;
+    let (online, rest) = rest
+        .split_once(|c: char| !c.is_ascii_digit())
+        .ok_or_else(err)?;
+    let rest = rest.strip_prefix("of a max of ").unwrap_or(rest);
+    let max: String = rest.chars().take_while(char::is_ascii_digit).collect();
+    let names = match rest.split_once(':') {
+        Some((_, names)) => names
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => Vec::new(),
+    };
+    match (online.parse(), max.parse()) {
+        (Ok(online), Ok(max)) => Ok(PlayerInfo {
+            online,
+            max,
+            names: Some(names),
+        }),
+        _ => Err(err()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeSlp;
+
+    fn status(port: u16, protocol_version: Option<i32>, probe_starting: bool) -> Status {
+        Status {
+            host: "127.0.0.1".to_string(),
+            port,
+            timeout: Duration::from_secs(5),
+            srv: None,
+            proxy: None,
+            protocol_version,
+            probe_starting,
+        }
+    }
+
+    #[tokio::test]
+    async fn status_reads_players() {
+        let server = FakeSlp::players(3, 20).await;
+        // Both with mc_query and with our own handshake.
+        for version in [None, Some(767)] {
+            let info = status(server.port, version, false)
+                .player_info()
+                .await
+                .unwrap();
+            assert_eq!((info.online, info.max), (3, 20));
+        }
+    }
+
+    #[tokio::test]
+    async fn status_reads_more_online_than_max() {
+        let server = FakeSlp::players(25, 20).await;
+        let info = status(server.port, None, false)
+            .player_info()
+            .await
+            .unwrap();
+        assert_eq!((info.online, info.max), (25, 20));
+    }
+
+    #[tokio::test]
+    async fn status_reads_zero_max() {
+        let server = FakeSlp::players(3, 0).await;
+        let info = status(server.port, None, false)
+            .player_info()
+            .await
+            .unwrap();
+        assert_eq!((info.online, info.max), (3, 0));
+    }
+
+    #[tokio::test]
+    async fn status_tells_starting_from_down() {
+        // A server that is starting accepts connections but can't answer yet.
+        let server = FakeSlp::start("Loading").await;
+        let e = status(server.port, None, true)
+            .player_info()
+            .await
+            .unwrap_err();
+        assert!(e.is::<Starting>());
+
+        // Nothing listens on a port that was just released.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let e = status(port, None, true).player_info().await.unwrap_err();
+        assert!(!e.is::<Starting>());
+    }
+}
@@ -0,0 +1,256 @@
+//! Resolution of the `_minecraft._tcp` SRV records that Java servers use to
+//! advertise their real host and port.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use hickory_resolver::TokioResolver;
+use hickory_resolver::proto::rr::RData;
+use std::net::IpAddr;
+
+/// An SRV record, pointing at the host and port a service is served on.
+#[derive(Clone, Debug)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// The host, without the trailing dot.
+    pub target: String,
+}
+
+/// The DNS lookups an [`SrvResolver`] makes. A name without records is not
+/// an error, but an empty answer.
+#[async_trait]
+pub trait Lookup: Send + Sync {
+    /// The SRV records of `name`.
+    async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>>;
+
+    /// The A and AAAA addresses of `host`.
+    async fn addresses(&self, host: &str) -> Result<Vec<IpAddr>>;
+}
+
+/// Looks up records with hickory, caching answers for their TTL so that
+/// polling every interval doesn't query DNS every interval.
+struct Hickory(TokioResolver);
+
+#[async_trait]
+impl Lookup for Hickory {
+    async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+        match self.0.srv_lookup(name).await {
+            Ok(lookup) => Ok(lookup
+                .answers()
+                .iter()
+                .filter_map(|r| match &r.data {
+                    RData::SRV(srv) => Some(SrvRecord {
+                        priority: srv.priority,
+                        weight: srv.weight,
+                        port: srv.port,
+                        target: srv.target.to_utf8().trim_end_matches('.').to_string(),
+                    }),
+                    _ => None,
+                })
+                .collect()),
+            Err(e) if e.is_no_records_found() => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn addresses(&self, host: &str) -> Result<Vec<IpAddr>> {
+        match self.0.lookup_ip(host).await {
+            Ok(lookup) => Ok(lookup.iter().collect()),
+            Err(e) if e.is_no_records_found() => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Resolves the SRV records of servers to the address and port to ping.
+pub struct SrvResolver {
+    lookup: Box<dyn Lookup>,
+}
+
+impl SrvResolver {
+    /// Create a resolver from the system configuration (`/etc/resolv.conf`).
+    pub fn new() -> Result<Self> {
+        let resolver = TokioResolver::builder_tokio()?.build()?;
+        Ok(Self::with_lookup(Hickory(resolver)))
+    }
+
+    /// Create a resolver that makes its lookups with `lookup`.
+    pub fn with_lookup(lookup: impl Lookup + 'static) -> Self {
+        SrvResolver {
+            lookup: Box::new(lookup),
+        }
+    }
+
+    /// Resolve `_minecraft._tcp.<host>` to the address of its target and its
+    /// port, falling back to `host` and `port` if there is no SRV record, or
+    /// its target has no address. The port of a record takes precedence over
+    /// `port`.
+    pub async fn resolve(&self, host: &str, port: u16) -> (String, u16) {
+        let name = format!("_minecraft._tcp.{host}");
+        let fallback = || (host.to_string(), port);
+        let records = match self.lookup.srv(&name).await {
+            Ok(records) => records,
+            Err(e) => {
+                tracing::warn!("SRV lookup for {name} failed, using {host}:{port}: {e}");
+                return fallback();
+            }
+        };
+        // Prefer the lowest priority, then the highest weight.
+        let Some(srv) = records
+            .into_iter()
+            .min_by_key(|srv| (srv.priority, std::cmp::Reverse(srv.weight)))
+        else {
+            tracing::debug!("No SRV record for {name}, using {host}:{port}.");
+            return fallback();
+        };
+        tracing::debug!("Resolved {name} to {}:{}.", srv.target, srv.port);
+
+        // A record pointing nowhere is as good as none.
+        let address = match self.lookup.addresses(&srv.target).await {
+            Ok(addresses) => addresses.into_iter().next(),
+            Err(e) => {
+                tracing::warn!(
+                    "Address lookup for {} failed, using {host}:{port}: {e}",
+                    srv.target
+                );
+                return fallback();
+            }
+        };
+        let Some(address) = address else {
+            tracing::warn!(
+                "SRV target {} has no address, using {host}:{port}.",
+                srv.target
+            );
+            return fallback();
+        };
+        tracing::debug!("Resolved {} to {address}.", srv.target);
+        if srv.port != port {
+            tracing::debug!(
+                "Using port {} of the SRV record instead of {port}.",
+                srv.port
+            );
+        }
+        (address.to_string(), srv.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::collections::HashMap;
+
+    /// Answers from fixed records, failing for any name that is mapped to
+    /// `None`.
+    struct Records {
+        srv: HashMap<&'static str, Option<Vec<SrvRecord>>>,
+        addresses: HashMap<&'static str, Option<Vec<IpAddr>>>,
+    }
+
+    #[async_trait]
+    impl Lookup for Records {
+        async fn srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+            match self.srv.get(name) {
+                Some(Some(records)) => Ok(records.clone()),
+                Some(None) => Err(anyhow!("SERVFAIL")),
+                None => Ok(Vec::new()),
+            }
+        }
+
+        async fn addresses(&self, host: &str) -> Result<Vec<IpAddr>> {
+            match self.addresses.get(host) {
+                Some(Some(addresses)) => Ok(addresses.clone()),
+                Some(None) => Err(anyhow!("SERVFAIL")),
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
+    fn record(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port,
+            target: target.to_string(),
+        }
+    }
+
+    fn with_records(
+        srv: Option<Vec<SrvRecord>>,
+        addresses: &[(&'static str, Option<Vec<IpAddr>>)],
+    ) -> SrvResolver {
+        SrvResolver::with_lookup(Records {
+            srv: HashMap::from([("_minecraft._tcp.mc.example.com", srv)]),
+            addresses: addresses.iter().cloned().collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn uses_port_and_address_of_record() {
+        let resolver = with_records(
+            Some(vec![record(0, 5, 25570, "node1.example.com")]),
+            &[("node1.example.com", Some(vec!["10.0.0.1".parse().unwrap()]))],
+        );
+        assert_eq!(
+            resolver.resolve("mc.example.com", 25565).await,
+            ("10.0.0.1".to_string(), 25570)
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_ipv6_target() {
+        let resolver = with_records(
+            Some(vec![record(0, 5, 25565, "node1.example.com")]),
+            &[("node1.example.com", Some(vec!["fd00::1".parse().unwrap()]))],
+        );
+        assert_eq!(
+            resolver.resolve("mc.example.com", 25565).await,
+            ("fd00::1".to_string(), 25565)
+        );
+    }
+
+    #[tokio::test]
+    async fn prefers_lowest_priority_then_highest_weight() {
+        let resolver = with_records(
+            Some(vec![
+                record(10, 100, 25571, "node1.example.com"),
+                record(0, 1, 25572, "node2.example.com"),
+                record(0, 50, 25573, "node3.example.com"),
+            ]),
+            &[
+                ("node1.example.com", Some(vec!["10.0.0.1".parse().unwrap()])),
+                ("node2.example.com", Some(vec!["10.0.0.2".parse().unwrap()])),
+                ("node3.example.com", Some(vec!["10.0.0.3".parse().unwrap()])),
+            ],
+        );
+        assert_eq!(
+            resolver.resolve("mc.example.com", 25565).await,
+            ("10.0.0.3".to_string(), 25573)
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_record() {
+        let resolver = with_records(Some(Vec::new()), &[]);
+        assert_eq!(
+            resolver.resolve("mc.example.com", 25565).await,
+            ("mc.example.com".to_string(), 25565)
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_lookups_fail() {
+        let fallback = ("mc.example.com".to_string(), 25565);
+        // The SRV lookup itself.
+        let resolver = with_records(None, &[]);
+        assert_eq!(resolver.resolve("mc.example.com", 25565).await, fallback);
+
+        // The address of its target, which neither keeps the port.
+        let srv = || Some(vec![record(0, 5, 25570, "node1.example.com")]);
+        let resolver = with_records(srv(), &[("node1.example.com", None)]);
+        assert_eq!(resolver.resolve("mc.example.com", 25565).await, fallback);
+        let resolver = with_records(srv(), &[("node1.example.com", Some(Vec::new()))]);
+        assert_eq!(resolver.resolve("mc.example.com", 25565).await, fallback);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::config::{CircuitBreaker, Debounce};
+use std::time::{Duration, Instant};
+
+/// What the controller remembers about each server between updates.
+pub struct ServerState {
+    /// Whether the PDB was last set to protect the server.
+    pub last_has_players: bool,
+    /// A change away from `last_has_players` that hasn't been committed yet.
+    pending: Option<Pending>,
+    /// How many queries in a row have failed.
+    failures: u32,
+    /// Whether any query has succeeded yet.
+    queried: bool,
+    /// When the server was first seen up since it was last down.
+    up_since: Option<Instant>,
+    /// When the server last met its threshold.
+    last_active: Option<Instant>,
+    /// When the server was last seen with players, if it has been empty since.
+    empty_since: Option<Instant>,
+    /// When the last change was committed.
+    last_patch: Option<Instant>,
+    /// Whether the PDB was paused at the last update.
+    paused: bool,
+    /// Set while the PDB is missing, to back off from updating it.
+    not_found: Option<NotFound>,
+    /// Set while the server keeps failing, to stop querying it for a while.
+    tripped: Option<Tripped>,
+}
+
+/// Whether a server is queried, after failing too many times in a row.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BreakerState {
+    /// The server is queried as usual.
+    #[default]
+    Closed,
+    /// The server is skipped until its cooldown is over.
+    Open,
+    /// The cooldown is over, and the next query decides whether it closes.
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn name(self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// A circuit breaker that has opened.
+struct Tripped {
+    /// How many times in a row it opened, including probes that failed.
+    trips: u32,
+    retry_at: Instant,
+}
+
+/// Backing off from a PDB that doesn't exist.
+struct NotFound {
+    attempts: u32,
+    retry_at: Instant,
+}
+
+/// A state change that is still being debounced.
+struct Pending {
+    cycles: u32,
+    since: Instant,
+}
+
+impl ServerState {
+    pub fn new(last_has_players: bool) -> Self {
+        ServerState {
+            last_has_players,
+            pending: None,
+            failures: 0,
+            queried: false,
+            up_since: None,
+            last_active: None,
+            empty_since: None,
+            last_patch: None,
+            paused: false,
+            not_found: None,
+            tripped: None,
+        }
+    }
+
+    /// Restore a state saved by a previous run.
+    pub fn restore(last_has_players: bool, failures: u32) -> Self {
+        ServerState {
+            failures,
+            ..ServerState::new(last_has_players)
+        }
+    }
+
+    /// Observe the current player state, returning whether it differs from
+    /// the last committed state and should now be committed.
+    pub fn observe(&mut self, has_players: bool, debounce: &Debounce) -> bool {
+        if has_players == self.last_has_players {
+            self.pending = None;
+            return false;
+        }
+        // Protecting the server is immediate unless configured otherwise.
+        if has_players && !debounce.protect {
+            return true;
+        }
+
+        let pending = self.pending.get_or_insert_with(|| Pending {
+            cycles: 0,
+            since: Instant::now(),
+        });
+        pending.cycles += 1;
+        // `since` is when the server became empty, for the grace period.
+        let elapsed = pending.since.elapsed();
+        pending.cycles >= debounce.cycles
+            && elapsed >= debounce.duration
+            && (has_players || elapsed >= debounce.empty_grace)
+    }
+
+    /// Record that a new state was applied.
+    pub fn commit(&mut self, has_players: bool) {
+        self.last_has_players = has_players;
+        self.pending = None;
+        self.last_patch = Some(Instant::now());
+    }
+
+    /// Take on the state the PDB was found in, e.g. after it was changed by
+    /// hand, discarding any pending change.
+    pub fn resync(&mut self, last_has_players: bool) {
+        self.last_has_players = last_has_players;
+        self.pending = None;
+    }
+
+    /// Record whether the PDB is paused, returning whether that changed.
+    pub fn set_paused(&mut self, paused: bool) -> bool {
+        std::mem::replace(&mut self.paused, paused) != paused
+    }
+
+    /// How long ago the last change was committed, if one has been.
+    pub fn since_last_patch(&self) -> Option<Duration> {
+        self.last_patch.map(|t| t.elapsed())
+    }
+
+    /// Record a failed query, returning how many have failed in a row.
+    pub fn record_failure(&mut self) -> u32 {
+        self.failures += 1;
+        self.failures
+    }
+
+    /// Record a successful query, ending any streak of failures.
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.queried = true;
+        self.up_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Record that the server is considered down, restarting its uptime.
+    pub fn record_down(&mut self) {
+        self.up_since = None;
+    }
+
+    /// How long the server has been up, if it is.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.up_since.map(|t| t.elapsed())
+    }
+
+    /// Record that the PDB doesn't exist, returning how long to wait before
+    /// the next update. The wait doubles from `interval` up to `max`.
+    pub fn record_not_found(&mut self, interval: Duration, max: Duration) -> Duration {
+        let attempts = self.not_found.as_ref().map_or(0, |n| n.attempts);
+        let delay = interval
+            .saturating_mul(2u32.saturating_pow(attempts))
+            .min(max);
+        self.not_found = Some(NotFound {
+            attempts: attempts + 1,
+            retry_at: Instant::now() + delay,
+        });
+        delay
+    }
+
+    /// Record a successful update, returning whether the PDB was missing.
+    pub fn record_found(&mut self) -> bool {
+        self.not_found.take().is_some()
+    }
+
+    /// How long is left before a missing PDB should be updated again.
+    pub fn not_found_wait(&self) -> Option<Duration> {
+        self.not_found
+            .as_ref()
+            .map(|n| n.retry_at.saturating_duration_since(Instant::now()))
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Open the circuit breaker, returning how long the server is skipped.
+    /// The cooldown doubles each time it opens again without closing.
+    pub fn trip_breaker(&mut self, breaker: &CircuitBreaker) -> Duration {
+        let trips = self.tripped.as_ref().map_or(0, |t| t.trips);
+        let cooldown = breaker
+            .cooldown
+            .saturating_mul(2u32.saturating_pow(trips))
+            .min(breaker.max_cooldown);
+        self.tripped = Some(Tripped {
+            trips: trips + 1,
+            retry_at: Instant::now() + cooldown,
+        });
+        cooldown
+    }
+
+    /// Close the circuit breaker, returning whether it was open.
+    pub fn reset_breaker(&mut self) -> bool {
+        self.tripped.take().is_some()
+    }
+
+    /// The state of the circuit breaker right now.
+    pub fn breaker(&self) -> BreakerState {
+        match &self.tripped {
+            None => BreakerState::Closed,
+            Some(t) if Instant::now() < t.retry_at => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    /// How long is left before the open circuit breaker lets a probe through.
+    pub fn breaker_wait(&self) -> Option<Duration> {
+        self.tripped
+            .as_ref()
+            .map(|t| t.retry_at.saturating_duration_since(Instant::now()))
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Record that the server meets its threshold right now.
+    pub fn record_active(&mut self) {
+        self.last_active = Some(Instant::now());
+    }
+
+    /// How long ago the server last met its threshold, if it has.
+    pub fn since_active(&self) -> Option<Duration> {
+        self.last_active.map(|t| t.elapsed())
+    }
+
+    /// Record how many players are online right now.
+    pub fn record_online(&mut self, online: u32) {
+        if online == 0 {
+            self.empty_since.get_or_insert_with(Instant::now);
+        } else {
+            self.empty_since = None;
+        }
+    }
+
+    /// How long the server has been seen without any players, if it is empty.
+    pub fn empty_for(&self) -> Option<Duration> {
+        self.empty_since.map(|t| t.elapsed())
+    }
+
+    /// How many queries in a row have failed.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether any query has succeeded since the server was first seen.
+    pub fn has_queried(&self) -> bool {
+        self.queried
+    }
+
+    /// How long the pending state has been observed, for logging.
+    pub fn pending_cycles(&self) -> u32 {
+        self.pending.as_ref().map_or(0, |p| p.cycles)
+    }
+}
@@ -0,0 +1,78 @@
+//! Counters of what the controller did over its lifetime, summarized when it
+//! shuts down to confirm that it was actually doing work.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub struct Stats {
+    started: Instant,
+    cycles: AtomicU64,
+    /// Changes of a server's player state, including those of a dry run.
+    transitions: AtomicU64,
+    query_failures: AtomicU64,
+    patches_succeeded: AtomicU64,
+    patches_failed: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            cycles: AtomicU64::new(0),
+            transitions: AtomicU64::new(0),
+            query_failures: AtomicU64::new(0),
+            patches_succeeded: AtomicU64::new(0),
+            patches_failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the start of an update of every server.
+    pub fn record_cycle(&self) {
+        self.cycles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_transition(&self) {
+        self.transitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a query that failed after every retry.
+    pub fn record_query_failure(&self) {
+        self.query_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_patch(&self, ok: bool) {
+        let counter = if ok {
+            &self.patches_succeeded
+        } else {
+            &self.patches_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Log the counters and uptime as a single line.
+    pub fn log_summary(&self) {
+        let uptime = Duration::from_secs(self.started.elapsed().as_secs());
+        let cycles = self.cycles.load(Ordering::Relaxed);
+        let transitions = self.transitions.load(Ordering::Relaxed);
+        let query_failures = self.query_failures.load(Ordering::Relaxed);
+        let patches_succeeded = self.patches_succeeded.load(Ordering::Relaxed);
+        let patches_failed = self.patches_failed.load(Ordering::Relaxed);
+        tracing::info!(
+            uptime_seconds = uptime.as_secs(),
+            cycles,
+            transitions,
+            query_failures,
+            patches_succeeded,
+            patches_failed,
+            "Ran {cycles} update(s) over {uptime:?}, with {transitions} state transition(s), \
+             {query_failures} failed query(ies), and {patches_succeeded} successful and \
+             {patches_failed} failed patch(es)."
+        );
+    }
+}
@@ -0,0 +1,892 @@
+//! What is changed to protect a server from disruption, either a PDB, a
+//! configured kind of custom resource, or an annotation on its pods. Each kind
+//! of object implements [`ProtectionTarget`], and [`for_server`] builds the one
+//! for a server.
+
+use crate::config::{
+    Config, CustomTarget, MaxUnavailable, MinAvailable, PatchStrategy, PdbField, ServerConfig,
+    TargetType, Threshold,
+};
+use crate::error::MgrError;
+use async_trait::async_trait;
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::core::v1::{ObjectReference, Pod};
+use k8s_openapi::api::policy::v1::{PodDisruptionBudget, PodDisruptionBudgetSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kube::Resource;
+use kube::api::{
+    Api, ApiResource, DynamicObject, GroupVersionKind, ListParams, Patch, PatchParams, PostParams,
+};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Set to `"true"` on a PDB to stop managing it, e.g. during maintenance.
+const PAUSED_ANNOTATION: &str = "mc-pdb-mgr/paused";
+/// Override the configured threshold for a single PDB.
+const MIN_PLAYERS_ANNOTATION: &str = "mc-pdb-mgr/min-players";
+const MIN_PLAYERS_PERCENT_ANNOTATION: &str = "mc-pdb-mgr/min-players-percent";
+/// Whether the cluster autoscaler may evict a pod to scale down its node.
+const SAFE_TO_EVICT_ANNOTATION: &str = "cluster-autoscaler.kubernetes.io/safe-to-evict";
+
+/// Something that can protect a server from voluntary disruption.
+#[async_trait]
+pub trait ProtectionTarget: Send + Sync {
+    /// Protect the server, or allow it to be disrupted.
+    async fn set_protected(&self, protected: bool) -> Result<(), MgrError>;
+
+    /// Whether the server is currently protected.
+    async fn current_protected(&self) -> Result<bool, MgrError>;
+
+    /// The field and value that protect the server, or allow it to be
+    /// disrupted, like `maxUnavailable=0`.
+    fn setting(&self, protected: bool) -> String;
+
+    /// The annotations that adjust how the server is managed, such as pausing
+    /// it.
+    async fn current_annotations(&self) -> Result<BTreeMap<String, String>, MgrError> {
+        Ok(BTreeMap::new())
+    }
+
+    /// The object to record Events on, once it has been changed.
+    fn event_object(&self) -> Option<ObjectReference> {
+        None
+    }
+}
+
+/// A PodDisruptionBudget, protecting the server through one of its fields.
+pub struct Pdb {
+    client: kube::Client,
+    namespace: String,
+    api: Api<PodDisruptionBudget>,
+    pdb_name: String,
+    field: PdbField,
+    max_unavailable: MaxUnavailable,
+    min_available: MinAvailable,
+    strategy: PatchStrategy,
+    field_manager: String,
+    dry_run: bool,
+    /// The selector to create a missing PDB with, if enabled.
+    create: Option<BTreeMap<String, String>>,
+    /// The PDB as of the last patch.
+    patched: Mutex<Option<ObjectReference>>,
+}
+
+#[async_trait]
+impl ProtectionTarget for Pdb {
+    async fn set_protected(&self, protected: bool) -> Result<(), MgrError> {
+        let pdb_name = &self.pdb_name;
+        if self.dry_run {
+            tracing::info!(pdb_name, "Dry run: would set {}.", self.setting(protected));
+            return Ok(());
+        }
+        let value = if protected && self.uses_replicas() {
+            let pdb = self.get().await?;
+            let selector = pdb.spec.and_then(|s| s.selector);
+            self.replicas_min_available(selector.as_ref()).await
+        } else {
+            pdb_field_value(
+                self.field,
+                &self.max_unavailable,
+                &self.min_available,
+                protected,
+            )
+        };
+
+        // Construct the patch.
+        let spec = serde_json::json!({
+            self.field.name(): value
+        });
+        let params = PatchParams {
+            field_manager: Some(self.field_manager.clone()),
+            ..Default::default()
+        };
+        let (patch, params) = match self.strategy {
+            PatchStrategy::Merge => (Patch::Merge(serde_json::json!({ "spec": spec })), params),
+            PatchStrategy::Strategic => (
+                Patch::Strategic(serde_json::json!({ "spec": spec })),
+                params,
+            ),
+            // "add" replaces the field, but unlike "replace" doesn't fail if
+            // it isn't set yet.
+            PatchStrategy::Json => {
+                let ops = serde_json::json!([{
+                    "op": "add",
+                    "path": format!("/spec/{}", self.field.name()),
+                    "value": value
+                }]);
+                let patch: json_patch::Patch =
+                    serde_json::from_value(ops).expect("a valid JSON patch");
+                (Patch::Json(patch), params)
+            }
+            // Only the managed field is included, so that other managers of the
+            // PDB keep ownership of everything else.
+            PatchStrategy::Apply => (
+                Patch::Apply(serde_json::json!({
+                    "apiVersion": "policy/v1",
+                    "kind": "PodDisruptionBudget",
+                    "metadata": { "name": pdb_name },
+                    "spec": spec
+                })),
+                PatchParams::apply(&self.field_manager).force(),
+            ),
+        };
+        // Patch it.
+        match self.api.patch(pdb_name, &params, &patch).await {
+            Ok(pdb) => {
+                tracing::debug!(pdb_name, "PodDisruptionBudget patched successfully.");
+                *self.patched.lock().unwrap() = Some(pdb.object_ref(&()));
+                Ok(())
+            }
+            Err(kube::Error::Api(s)) if s.is_not_found() => Err(MgrError::PdbNotFound {
+                pdb_name: pdb_name.clone(),
+            }),
+            Err(e) if matches!(&e, kube::Error::Api(s) if s.is_conflict()) => {
+                Err(MgrError::PatchConflict {
+                    name: format!("PodDisruptionBudget {pdb_name}"),
+                    source: Box::new(e),
+                })
+            }
+            Err(e) => Err(MgrError::PatchFailed {
+                pdb_name: pdb_name.clone(),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    async fn current_protected(&self) -> Result<bool, MgrError> {
+        let pdb_name = &self.pdb_name;
+        match self.api.get(pdb_name).await {
+            Ok(pdb) => Ok(is_protected(
+                &pdb,
+                self.field,
+                &self.max_unavailable,
+                &self.min_available,
+            )),
+            Err(kube::Error::Api(s)) if s.is_not_found() => match &self.create {
+                // A created PDB starts out protecting the server.
+                Some(labels) => self.create(labels).await.map(|()| true),
+                None => Err(MgrError::PdbNotFound {
+                    pdb_name: pdb_name.clone(),
+                }),
+            },
+            Err(e) => Err(MgrError::GetFailed {
+                pdb_name: pdb_name.clone(),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    fn setting(&self, protected: bool) -> String {
+        // The replicas are only read when patching.
+        if protected && self.uses_replicas() {
+            return format!("{}=replicas", self.field.name());
+        }
+        format!(
+            "{}={}",
+            self.field.name(),
+            serde_json::json!(pdb_field_value(
+                self.field,
+                &self.max_unavailable,
+                &self.min_available,
+                protected
+            ))
+        )
+    }
+
+    async fn current_annotations(&self) -> Result<BTreeMap<String, String>, MgrError> {
+        match self.api.get(&self.pdb_name).await {
+            Ok(pdb) => Ok(pdb.metadata.annotations.unwrap_or_default()),
+            // A missing PDB is handled like any other update.
+            Err(kube::Error::Api(s)) if s.is_not_found() => Ok(BTreeMap::new()),
+            Err(e) => Err(MgrError::GetFailed {
+                pdb_name: self.pdb_name.clone(),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    fn event_object(&self) -> Option<ObjectReference> {
+        self.patched.lock().unwrap().clone()
+    }
+}
+
+impl Pdb {
+    pub fn new(client: kube::Client, namespace: &str, config: &Config, pdb_name: &str) -> Self {
+        Pdb {
+            api: Api::namespaced(client.clone(), namespace),
+            client,
+            namespace: namespace.to_string(),
+            pdb_name: pdb_name.to_string(),
+            field: config.pdb_field,
+            max_unavailable: config.max_unavailable.clone(),
+            min_available: config.min_available.clone(),
+            strategy: config.patch_strategy,
+            field_manager: config.field_manager.clone(),
+            dry_run: config.dry_run,
+            create: config.create_pdb.clone(),
+            patched: Mutex::new(None),
+        }
+    }
+
+    /// Create the missing PDB. It starts out protecting the server, and the
+    /// first update allows disruption if there are no players.
+    async fn create(&self, labels: &BTreeMap<String, String>) -> Result<(), MgrError> {
+        let pdb_name = &self.pdb_name;
+        if self.dry_run {
+            tracing::info!(pdb_name, "Dry run: would create PodDisruptionBudget.");
+            return Ok(());
+        }
+        let value = Some(if self.uses_replicas() {
+            let selector = LabelSelector {
+                match_labels: Some(labels.clone()),
+                ..Default::default()
+            };
+            self.replicas_min_available(Some(&selector)).await
+        } else {
+            pdb_field_value(self.field, &self.max_unavailable, &self.min_available, true)
+        });
+        let pdb = PodDisruptionBudget {
+            metadata: ObjectMeta {
+                name: Some(pdb_name.clone()),
+                ..Default::default()
+            },
+            spec: Some(PodDisruptionBudgetSpec {
+                selector: Some(LabelSelector {
+                    match_labels: Some(labels.clone()),
+                    ..Default::default()
+                }),
+                max_unavailable: value
+                    .clone()
+                    .filter(|_| self.field == PdbField::MaxUnavailable),
+                min_available: value.filter(|_| self.field == PdbField::MinAvailable),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let params = PostParams {
+            field_manager: Some(self.field_manager.clone()),
+            ..Default::default()
+        };
+        match self.api.create(&params, &pdb).await {
+            Ok(_) => {
+                tracing::info!(pdb_name, "Created missing PodDisruptionBudget.");
+                Ok(())
+            }
+            Err(e) => Err(MgrError::CreateFailed {
+                pdb_name: pdb_name.clone(),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    async fn get(&self) -> Result<PodDisruptionBudget, MgrError> {
+        let pdb_name = &self.pdb_name;
+        match self.api.get(pdb_name).await {
+            Ok(pdb) => Ok(pdb),
+            Err(kube::Error::Api(s)) if s.is_not_found() => Err(MgrError::PdbNotFound {
+                pdb_name: pdb_name.clone(),
+            }),
+            Err(e) => Err(MgrError::GetFailed {
+                pdb_name: pdb_name.clone(),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    /// Whether the protected minAvailable is read from the workload.
+    fn uses_replicas(&self) -> bool {
+        self.field == PdbField::MinAvailable && self.min_available.from_replicas
+    }
+
+    /// The minAvailable that protects every replica of the workload the PDB
+    /// selects, or the configured one if it can't be found.
+    async fn replicas_min_available(&self, selector: Option<&LabelSelector>) -> IntOrString {
+        let pdb_name = &self.pdb_name;
+        match self.workload_replicas(selector).await {
+            Ok((workload, replicas)) => {
+                tracing::debug!(pdb_name, "{workload} has {replicas} replica(s).");
+                IntOrString::Int(replicas)
+            }
+            Err(e) => {
+                let fallback = &self.min_available.protected;
+                tracing::warn!(
+                    pdb_name,
+                    "Can't read the replicas of the workload of PodDisruptionBudget {pdb_name}: {e}, using minAvailable={}.",
+                    serde_json::json!(fallback)
+                );
+                fallback.clone()
+            }
+        }
+    }
+
+    /// The replicas of the only StatefulSet or Deployment whose pods match the
+    /// selector, and which one it is.
+    async fn workload_replicas(
+        &self,
+        selector: Option<&LabelSelector>,
+    ) -> Result<(String, i32), String> {
+        let selector = selector.ok_or("it has no selector")?;
+        if selector
+            .match_expressions
+            .as_ref()
+            .is_some_and(|e| !e.is_empty())
+        {
+            return Err("its selector uses matchExpressions".to_string());
+        }
+        let labels = selector
+            .match_labels
+            .as_ref()
+            .filter(|labels| !labels.is_empty())
+            .ok_or("its selector matches every pod")?;
+        let selects = |meta: Option<&ObjectMeta>| {
+            let pod_labels = meta.and_then(|m| m.labels.as_ref());
+            labels
+                .iter()
+                .all(|(k, v)| pod_labels.and_then(|l| l.get(k)) == Some(v))
+        };
+
+        let params = ListParams::default();
+        let stateful_sets = Api::<StatefulSet>::namespaced(self.client.clone(), &self.namespace)
+            .list(&params)
+            .await
+            .map_err(|e| format!("failed to list StatefulSets: {e}"))?;
+        let deployments = Api::<Deployment>::namespaced(self.client.clone(), &self.namespace)
+            .list(&params)
+            .await
+            .map_err(|e| format!("failed to list Deployments: {e}"))?;
+        let stateful_sets = stateful_sets.items.into_iter().filter_map(|s| {
+            let spec = s
+                .spec
+                .filter(|spec| selects(spec.template.metadata.as_ref()))?;
+            Some((format!("StatefulSet {}", s.metadata.name?), spec.replicas))
+        });
+        let deployments = deployments.items.into_iter().filter_map(|d| {
+            let spec = d
+                .spec
+                .filter(|spec| selects(spec.template.metadata.as_ref()))?;
+            Some((format!("Deployment {}", d.metadata.name?), spec.replicas))
+        });
+        let workloads: Vec<_> = stateful_sets.chain(deployments).collect();
+        match workloads.as_slice() {
+            // Like Kubernetes, an unset replicas is one.
+            [(workload, replicas)] => Ok((workload.clone(), replicas.unwrap_or(1))),
+            [] => Err("no StatefulSet or Deployment has pods it selects".to_string()),
+            workloads => Err(format!(
+                "{} StatefulSets and Deployments have pods it selects",
+                workloads.len()
+            )),
+        }
+    }
+}
+
+/// A custom resource of any kind, protecting the server through a field set
+/// to one of two configured values.
+pub struct CustomResource {
+    api: Api<DynamicObject>,
+    resource: ApiResource,
+    name: String,
+    target: CustomTarget,
+    strategy: PatchStrategy,
+    field_manager: String,
+    dry_run: bool,
+    /// The object as of the last patch.
+    patched: Mutex<Option<ObjectReference>>,
+}
+
+#[async_trait]
+impl ProtectionTarget for CustomResource {
+    async fn set_protected(&self, protected: bool) -> Result<(), MgrError> {
+        let name = &self.name;
+        if self.dry_run {
+            tracing::info!(
+                pdb_name = name,
+                "Dry run: would set {} on {}.",
+                self.setting(protected),
+                self.resource.kind
+            );
+            return Ok(());
+        }
+
+        let value = self.value(protected).clone();
+        let params = PatchParams {
+            field_manager: Some(self.field_manager.clone()),
+            ..Default::default()
+        };
+        let (patch, params) = match self.strategy {
+            // Custom resources don't support strategic merge patches, which
+            // the configuration rejects.
+            PatchStrategy::Merge | PatchStrategy::Strategic => {
+                (Patch::Merge(self.nested(value)), params)
+            }
+            // Unlike the field itself, its parents must already exist.
+            PatchStrategy::Json => {
+                let ops = serde_json::json!([{
+                    "op": "add",
+                    "path": self.pointer(),
+                    "value": value
+                }]);
+                let patch: json_patch::Patch =
+                    serde_json::from_value(ops).expect("a valid JSON patch");
+                (Patch::Json(patch), params)
+            }
+            PatchStrategy::Apply => {
+                let mut object = serde_json::json!({
+                    "apiVersion": self.resource.api_version,
+                    "kind": self.resource.kind,
+                    "metadata": { "name": name }
+                });
+                if let (Some(object), serde_json::Value::Object(fields)) =
+                    (object.as_object_mut(), self.nested(value))
+                {
+                    object.extend(fields);
+                }
+                (
+                    Patch::Apply(object),
+                    PatchParams::apply(&self.field_manager).force(),
+                )
+            }
+        };
+        match self.api.patch(name, &params, &patch).await {
+            Ok(object) => {
+                tracing::debug!(
+                    pdb_name = name,
+                    "{} patched successfully.",
+                    self.resource.kind
+                );
+                *self.patched.lock().unwrap() = Some(object.object_ref(&self.resource));
+                Ok(())
+            }
+            Err(kube::Error::Api(s)) if s.is_not_found() => Err(MgrError::TargetNotFound {
+                kind: self.resource.kind.clone(),
+                name: name.clone(),
+            }),
+            Err(e) if matches!(&e, kube::Error::Api(s) if s.is_conflict()) => {
+                Err(MgrError::PatchConflict {
+                    name: format!("{} {name}", self.resource.kind),
+                    source: Box::new(e),
+                })
+            }
+            Err(e) => Err(MgrError::TargetPatchFailed {
+                kind: self.resource.kind.clone(),
+                name: name.clone(),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    async fn current_protected(&self) -> Result<bool, MgrError> {
+        let object = self.get().await?;
+        Ok(object.data.pointer(&self.pointer()) == Some(&self.target.protected_value))
+    }
+
+    fn setting(&self, protected: bool) -> String {
+        format!("{}={}", self.target.field.join("."), self.value(protected))
+    }
+
+    async fn current_annotations(&self) -> Result<BTreeMap<String, String>, MgrError> {
+        Ok(self.get().await?.metadata.annotations.unwrap_or_default())
+    }
+
+    fn event_object(&self) -> Option<ObjectReference> {
+        self.patched.lock().unwrap().clone()
+    }
+}
+
+impl CustomResource {
+    pub fn new(client: kube::Client, config: &Config, target: &CustomTarget, name: &str) -> Self {
+        let gvk = GroupVersionKind::gvk(&target.group, &target.version, &target.kind);
+        let resource = match &target.plural {
+            Some(plural) => ApiResource::from_gvk_with_plural(&gvk, plural),
+            None => ApiResource::from_gvk(&gvk),
+        };
+        CustomResource {
+            api: Api::namespaced_with(client, &config.pod_namespace, &resource),
+            resource,
+            name: name.to_string(),
+            target: target.clone(),
+            strategy: config.patch_strategy,
+            field_manager: config.field_manager.clone(),
+            dry_run: config.dry_run,
+            patched: Mutex::new(None),
+        }
+    }
+
+    async fn get(&self) -> Result<DynamicObject, MgrError> {
+        match self.api.get(&self.name).await {
+            Ok(object) => Ok(object),
+            Err(kube::Error::Api(s)) if s.is_not_found() => Err(MgrError::TargetNotFound {
+                kind: self.resource.kind.clone(),
+                name: self.name.clone(),
+            }),
+            Err(e) => Err(MgrError::TargetGetFailed {
+                kind: self.resource.kind.clone(),
+                name: self.name.clone(),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    fn value(&self, protected: bool) -> &serde_json::Value {
+        if protected {
+            &self.target.protected_value
+        } else {
+            &self.target.unprotected_value
+        }
+    }
+
+    /// The field as a JSON pointer, like `/spec/drainAllowed`.
+    fn pointer(&self) -> String {
+        self.target
+            .field
+            .iter()
+            .map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1")))
+            .collect()
+    }
+
+    /// An object with just the field set to `value`, nested under its parents.
+    fn nested(&self, value: serde_json::Value) -> serde_json::Value {
+        self.target
+            .field
+            .iter()
+            .rev()
+            .fold(value, |value, key| serde_json::json!({ key: value }))
+    }
+}
+
+/// The pods matching a selector, protecting the server through the cluster
+/// autoscaler's safe-to-evict annotation, for workloads without a PDB.
+pub struct SafeToEvict {
+    api: Api<Pod>,
+    pdb_name: String,
+    selector: String,
+    field_manager: String,
+    dry_run: bool,
+    /// The last pod patched.
+    patched: Mutex<Option<ObjectReference>>,
+}
+
+#[async_trait]
+impl ProtectionTarget for SafeToEvict {
+    async fn set_protected(&self, protected: bool) -> Result<(), MgrError> {
+        let pdb_name = &self.pdb_name;
+        if self.dry_run {
+            tracing::info!(
+                pdb_name,
+                "Dry run: would set {} on pods matching {}.",
+                self.setting(protected),
+                self.selector
+            );
+            return Ok(());
+        }
+        let value = Self::value(protected);
+        let patch = serde_json::json!({
+            "metadata": { "annotations": { SAFE_TO_EVICT_ANNOTATION: value } }
+        });
+        let params = PatchParams {
+            field_manager: Some(self.field_manager.clone()),
+            ..Default::default()
+        };
+        // Pods that already have the value, like those that started since the
+        // last change, are left alone.
+        for pod in self.pods().await? {
+            let Some(pod_name) = pod.metadata.name.as_deref() else {
+                continue;
+            };
+            if Self::annotation(&pod) == Some(value) {
+                continue;
+            }
+            match self
+                .api
+                .patch(pod_name, &params, &Patch::Merge(&patch))
+                .await
+            {
+                Ok(pod) => {
+                    tracing::debug!(pdb_name, "Pod {pod_name} annotated successfully.");
+                    *self.patched.lock().unwrap() = Some(pod.object_ref(&()));
+                }
+                Err(e) => {
+                    return Err(MgrError::TargetPatchFailed {
+                        kind: "Pod".to_string(),
+                        name: pod_name.to_string(),
+                        source: Box::new(e),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn current_protected(&self) -> Result<bool, MgrError> {
+        let value = Self::value(true);
+        Ok(self
+            .pods()
+            .await?
+            .iter()
+            .all(|pod| Self::annotation(pod) == Some(value)))
+    }
+
+    fn setting(&self, protected: bool) -> String {
+        format!("{SAFE_TO_EVICT_ANNOTATION}={}", Self::value(protected))
+    }
+
+    fn event_object(&self) -> Option<ObjectReference> {
+        self.patched.lock().unwrap().clone()
+    }
+}
+
+impl SafeToEvict {
+    /// Select the server's pods with `selector`, in which `{pdb_name}` is
+    /// replaced with the server's PDB name.
+    pub fn new(
+        client: kube::Client,
+        namespace: &str,
+        config: &Config,
+        pdb_name: &str,
+        selector: &str,
+    ) -> Self {
+        SafeToEvict {
+            api: Api::namespaced(client, namespace),
+            pdb_name: pdb_name.to_string(),
+            selector: selector.replace("{pdb_name}", pdb_name),
+            field_manager: config.field_manager.clone(),
+            dry_run: config.dry_run,
+            patched: Mutex::new(None),
+        }
+    }
+
+    /// The selected pods, of which there must be at least one.
+    async fn pods(&self) -> Result<Vec<Pod>, MgrError> {
+        let params = ListParams::default().labels(&self.selector);
+        match self.api.list(&params).await {
+            Ok(pods) if pods.items.is_empty() => Err(MgrError::NoPodsSelected {
+                selector: self.selector.clone(),
+            }),
+            Ok(pods) => Ok(pods.items),
+            Err(e) => Err(MgrError::TargetGetFailed {
+                kind: "Pods".to_string(),
+                name: format!("matching {}", self.selector),
+                source: Box::new(e),
+            }),
+        }
+    }
+
+    fn annotation(pod: &Pod) -> Option<&str> {
+        let annotations = pod.metadata.annotations.as_ref()?;
+        annotations
+            .get(SAFE_TO_EVICT_ANNOTATION)
+            .map(String::as_str)
+    }
+
+    /// The annotation's value, where a protected pod isn't safe to evict.
+    fn value(protected: bool) -> &'static str {
+        if protected { "false" } else { "true" }
+    }
+}
+
+/// Build the target that protects a server.
+pub fn for_server(
+    client: &kube::Client,
+    config: &Config,
+    server: &ServerConfig,
+) -> Box<dyn ProtectionTarget> {
+    if let (TargetType::SafeToEvictAnnotation, Some(selector)) =
+        (config.target_type, &config.target_pod_selector)
+    {
+        return Box::new(SafeToEvict::new(
+            client.clone(),
+            server.namespace(config),
+            config,
+            &server.pdb_name,
+            selector,
+        ));
+    }
+    match &config.custom_target {
+        Some(target) => Box::new(CustomResource::new(
+            client.clone(),
+            config,
+            target,
+            &server.pdb_name,
+        )),
+        None => Box::new(Pdb::new(
+            client.clone(),
+            server.namespace(config),
+            config,
+            &server.pdb_name,
+        )),
+    }
+}
+
+/// Whether a PDB's field is set to protect its server. A PDB without a spec,
+/// or with the field unset, doesn't protect it.
+pub fn is_protected(
+    pdb: &PodDisruptionBudget,
+    field: PdbField,
+    max_unavailable: &MaxUnavailable,
+    min_available: &MinAvailable,
+) -> bool {
+    let value = pdb.spec.as_ref().and_then(|s| match field {
+        PdbField::MaxUnavailable => s.max_unavailable.as_ref(),
+        PdbField::MinAvailable => s.min_available.as_ref(),
+    });
+    match field {
+        PdbField::MaxUnavailable => value.is_some_and(|v| max_unavailable.is_protected(v)),
+        PdbField::MinAvailable => value.is_some_and(|v| min_available.is_protected(v)),
+    }
+}
+
+/// Whether a PDB's annotations pause its management.
+pub fn is_paused(annotations: &BTreeMap<String, String>) -> bool {
+    annotations
+        .get(PAUSED_ANNOTATION)
+        .is_some_and(|v| v.eq_ignore_ascii_case("true"))
+}
+
+/// The threshold of a PDB, with the parts set by its annotations replacing
+/// those of `default`.
+pub fn annotated_threshold(
+    annotations: &BTreeMap<String, String>,
+    default: &Threshold,
+) -> Result<Threshold, String> {
+    let min_players: Option<u32> = annotations
+        .get(MIN_PLAYERS_ANNOTATION)
+        .map(|v| {
+            v.parse()
+                .map_err(|_| format!("invalid {MIN_PLAYERS_ANNOTATION} annotation {v}"))
+        })
+        .transpose()?;
+    let pct: Option<f64> = annotations
+        .get(MIN_PLAYERS_PERCENT_ANNOTATION)
+        .map(|v| match v.parse() {
+            Ok(pct) if pct > 0.0 && pct <= 1.0 => Ok(pct),
+            _ => Err(format!(
+                "invalid {MIN_PLAYERS_PERCENT_ANNOTATION} annotation {v}"
+            )),
+        })
+        .transpose()?;
+
+    Ok(match (default, min_players, pct) {
+        (_, None, None) => default.clone(),
+        // Combined thresholds keep whichever part isn't overridden.
+        (Threshold::Or(n, p), min_players, pct) => {
+            Threshold::Or(min_players.unwrap_or(*n), pct.unwrap_or(*p))
+        }
+        (Threshold::And(n, p), min_players, pct) => {
+            Threshold::And(min_players.unwrap_or(*n), pct.unwrap_or(*p))
+        }
+        (_, Some(n), None) => Threshold::Absolute(n),
+        (_, None, Some(pct)) => Threshold::Percent(pct),
+        (_, Some(_), Some(_)) => {
+            return Err(format!(
+                "{MIN_PLAYERS_ANNOTATION} and {MIN_PLAYERS_PERCENT_ANNOTATION} are mutually exclusive unless THRESHOLD_MODE is or/and"
+            ));
+        }
+    })
+}
+
+/// The value of a PDB field that protects a server, or allows disruption.
+pub fn pdb_field_value(
+    field: PdbField,
+    max_unavailable: &MaxUnavailable,
+    min_available: &MinAvailable,
+    protect: bool,
+) -> IntOrString {
+    match field {
+        PdbField::MaxUnavailable => max_unavailable.value(protect).clone(),
+        PdbField::MinAvailable if protect => min_available.protected.clone(),
+        PdbField::MinAvailable => IntOrString::Int(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_UNAVAILABLE: MaxUnavailable = MaxUnavailable {
+        protected: IntOrString::Int(0),
+        unprotected: IntOrString::Int(1),
+    };
+    const MIN_AVAILABLE: MinAvailable = MinAvailable {
+        protected: IntOrString::Int(1),
+        from_replicas: false,
+    };
+
+    fn pdb(spec: Option<PodDisruptionBudgetSpec>) -> PodDisruptionBudget {
+        PodDisruptionBudget {
+            spec,
+            ..Default::default()
+        }
+    }
+
+    fn max_unavailable(value: IntOrString) -> PodDisruptionBudget {
+        pdb(Some(PodDisruptionBudgetSpec {
+            max_unavailable: Some(value),
+            ..Default::default()
+        }))
+    }
+
+    fn min_available(value: IntOrString) -> PodDisruptionBudget {
+        pdb(Some(PodDisruptionBudgetSpec {
+            min_available: Some(value),
+            ..Default::default()
+        }))
+    }
+
+    fn protects(pdb: &PodDisruptionBudget, field: PdbField) -> bool {
+        is_protected(pdb, field, &MAX_UNAVAILABLE, &MIN_AVAILABLE)
+    }
+
+    #[test]
+    fn max_unavailable_zero_protects() {
+        let field = PdbField::MaxUnavailable;
+        assert!(protects(&max_unavailable(IntOrString::Int(0)), field));
+        assert!(!protects(&max_unavailable(IntOrString::Int(1)), field));
+    }
+
+    #[test]
+    fn max_unavailable_zero_percent_protects() {
+        let field = PdbField::MaxUnavailable;
+        let percent = |pct: &str| max_unavailable(IntOrString::String(pct.to_string()));
+        assert!(protects(&percent("0%"), field));
+        assert!(!protects(&percent("50%"), field));
+        assert!(!protects(&percent("100%"), field));
+    }
+
+    #[test]
+    fn missing_field_doesnt_protect() {
+        for field in [PdbField::MaxUnavailable, PdbField::MinAvailable] {
+            assert!(!protects(&pdb(None), field));
+            assert!(!protects(&pdb(Some(Default::default())), field));
+        }
+        // Only the managed field counts.
+        let pdb = max_unavailable(IntOrString::Int(0));
+        assert!(!protects(&pdb, PdbField::MinAvailable));
+    }
+
+    #[test]
+    fn min_available_protects() {
+        let field = PdbField::MinAvailable;
+        assert!(protects(&min_available(IntOrString::Int(1)), field));
+        assert!(!protects(&min_available(IntOrString::Int(0)), field));
+    }
+
+    #[test]
+    fn min_available_from_replicas_protects_unless_zero() {
+        let from_replicas = MinAvailable {
+            from_replicas: true,
+            ..MIN_AVAILABLE
+        };
+        let protects = |value| {
+            is_protected(
+                &min_available(value),
+                PdbField::MinAvailable,
+                &MAX_UNAVAILABLE,
+                &from_replicas,
+            )
+        };
+        assert!(protects(IntOrString::Int(3)));
+        assert!(protects(IntOrString::String("100%".to_string())));
+        assert!(!protects(IntOrString::Int(0)));
+        assert!(!protects(IntOrString::String("0%".to_string())));
+    }
+}
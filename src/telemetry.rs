@@ -0,0 +1,40 @@
+//! Export of tracing spans to an OpenTelemetry collector over OTLP.
+
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Spans are exported only when the standard OTLP endpoint variable is set.
+const ENDPOINT_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// Build a layer that exports spans over OTLP/HTTP, if an endpoint is
+/// configured. The provider must be shut down before exiting, so that the
+/// last spans are flushed.
+pub fn layer<S>() -> Result<Option<(OpenTelemetryLayer<S, SdkTracer>, SdkTracerProvider)>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if std::env::var_os(ENDPOINT_VAR).is_none() {
+        return Ok(None);
+    }
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(env!("CARGO_PKG_NAME"))
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    Ok(Some((
+        tracing_opentelemetry::layer().with_tracer(tracer),
+        provider,
+    )))
+}
@@ -0,0 +1,111 @@
+//! Fakes shared by the tests of several modules.
+
+use crate::ping::{packet, read_varint, write_varint};
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// A handshake received by a [`FakeSlp`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: i32,
+    pub host: String,
+    pub port: u16,
+    pub next_state: i32,
+}
+
+/// A server on an ephemeral port that answers the Server List Ping with a
+/// fixed status, and remembers the handshakes it received. It stops when
+/// dropped.
+pub struct FakeSlp {
+    pub port: u16,
+    handshakes: Arc<Mutex<Vec<Handshake>>>,
+    task: JoinHandle<()>,
+}
+
+impl FakeSlp {
+    /// Answer every status request with `status`, which doesn't have to be
+    /// valid JSON.
+    pub async fn start(status: impl Into<String>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handshakes = Arc::new(Mutex::new(Vec::new()));
+        let status = Arc::new(status.into());
+        let task = tokio::spawn({
+            let handshakes = handshakes.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (handshakes, status) = (handshakes.clone(), status.clone());
+                    tokio::spawn(async move {
+                        let _ = answer(stream, &handshakes, &status).await;
+                    });
+                }
+            }
+        });
+        FakeSlp {
+            port,
+            handshakes,
+            task,
+        }
+    }
+
+    /// A server reporting `online` of `max` players.
+    pub async fn players(online: u32, max: u32) -> Self {
+        Self::start(
+            serde_json::json!({
+                "version": {"name": "1.21", "protocol": 767},
+                "players": {"online": online, "max": max},
+                "description": {"text": "A Minecraft Server"},
+            })
+            .to_string(),
+        )
+        .await
+    }
+
+    pub fn handshakes(&self) -> Vec<Handshake> {
+        self.handshakes.lock().unwrap().clone()
+    }
+}
+
+impl Drop for FakeSlp {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn answer(
+    mut stream: TcpStream,
+    handshakes: &Mutex<Vec<Handshake>>,
+    status: &str,
+) -> Result<()> {
+    let body = read_packet(&mut stream).await?;
+    let mut body = body.as_slice();
+    read_varint(&mut body).await?;
+    let protocol_version = read_varint(&mut body).await?;
+    let mut host = vec![0u8; usize::try_from(read_varint(&mut body).await?)?];
+    body.read_exact(&mut host).await?;
+    let port = body.read_u16().await?;
+    let next_state = read_varint(&mut body).await?;
+    handshakes.lock().unwrap().push(Handshake {
+        protocol_version,
+        host: String::from_utf8(host)?,
+        port,
+        next_state,
+    });
+
+    // The status request has no payload.
+    read_packet(&mut stream).await?;
+    let mut response = Vec::new();
+    write_varint(&mut response, i32::try_from(status.len())?);
+    response.extend_from_slice(status.as_bytes());
+    stream.write_all(&packet(0x00, &response)).await?;
+    Ok(())
+}
+
+async fn read_packet(stream: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut body = vec![0u8; usize::try_from(read_varint(stream).await?)?];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
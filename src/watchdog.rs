@@ -0,0 +1,68 @@
+//! Exiting when the update loop stops iterating, e.g. because of a deadlock,
+//! so that Kubernetes restarts the pod instead of it silently protecting
+//! nothing.
+
+use crate::config::Config;
+use crate::leader::LeaderElector;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How often the last update is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When the update loop last completed an update.
+pub struct Watchdog {
+    last: Mutex<Instant>,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Watchdog::new()
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Watchdog {
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Record that an update completed.
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Exit the process once no update has completed within the configured
+/// multiple of the update interval, until shutdown.
+pub async fn run(
+    watchdog: Arc<Watchdog>,
+    config: Arc<RwLock<Config>>,
+    elector: Option<Arc<LeaderElector>>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+        }
+        // Only the leader updates, so the others have nothing to watch.
+        if elector.as_ref().is_some_and(|e| !e.is_leader()) {
+            watchdog.beat();
+            continue;
+        }
+        let (multiplier, interval) = {
+            let config = config.read().unwrap();
+            (config.watchdog_multiplier, config.longest_interval())
+        };
+        let elapsed = watchdog.last.lock().unwrap().elapsed();
+        if multiplier != 0 && elapsed > interval * multiplier {
+            tracing::error!(
+                "No update completed in {elapsed:?}, the update loop appears to be stuck. Exiting."
+            );
+            std::process::exit(1);
+        }
+    }
+}
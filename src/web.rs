@@ -0,0 +1,339 @@
+use crate::state::BreakerState;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+/// Tracks whether the main loop is still making progress.
+pub struct Health {
+    last_success: Mutex<Option<Instant>>,
+    max_age: Mutex<Duration>,
+}
+
+impl Health {
+    /// Readiness fails once the last successful update is older than `max_age`.
+    pub fn new(max_age: Duration) -> Self {
+        Health {
+            last_success: Mutex::new(None),
+            max_age: Mutex::new(max_age),
+        }
+    }
+
+    /// Change the maximum age, e.g. after the update interval is reloaded.
+    pub fn set_max_age(&self, max_age: Duration) {
+        *self.max_age.lock().unwrap() = max_age;
+    }
+
+    /// Record a successful status query and PDB update.
+    pub fn mark_success(&self) {
+        *self.last_success.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn is_ready(&self) -> bool {
+        self.last_success
+            .lock()
+            .unwrap()
+            .is_some_and(|t| t.elapsed() <= *self.max_age.lock().unwrap())
+    }
+}
+
+/// The upper bounds of the query duration histogram's buckets in seconds,
+/// Prometheus' defaults.
+const QUERY_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// What the controller last saw and did for a server.
+#[derive(Default)]
+struct ServerStatus {
+    players_online: Option<u32>,
+    players_max: Option<u32>,
+    players_needed: Option<f64>,
+    /// How `players_needed` was computed, like `2 OR 50% [5]`.
+    threshold: Option<String>,
+    /// What set the threshold, like a rule of THRESHOLD_SCHEDULE.
+    threshold_source: Option<String>,
+    has_players: bool,
+    last_query: Option<DateTime<Utc>>,
+    last_patch: Option<DateTime<Utc>>,
+    /// How long the last query took, whether or not it succeeded.
+    query_latency: Option<Duration>,
+    /// How many queries took at most each of [`QUERY_DURATION_BUCKETS`].
+    query_buckets: [u64; QUERY_DURATION_BUCKETS.len()],
+    /// How many queries were made, and how long they took in total.
+    query_count: u64,
+    query_seconds: f64,
+    /// How many committed changes protected the server.
+    to_protected: u64,
+    /// How many committed changes allowed its disruption.
+    to_unprotected: u64,
+    breaker: BreakerState,
+}
+
+/// The controller's view of each server by PDB name, served on `/status`.
+#[derive(Default)]
+pub struct Status {
+    servers: Mutex<BTreeMap<String, ServerStatus>>,
+    /// How long the last update of every server took.
+    last_cycle: Mutex<Option<Duration>>,
+}
+
+impl Status {
+    /// Record the state a PDB was found in when it started being managed.
+    pub fn record_initial(&self, pdb_name: &str, has_players: bool) {
+        let mut servers = self.servers.lock().unwrap();
+        servers.entry(pdb_name.to_string()).or_default().has_players = has_players;
+    }
+
+    /// Record a successful status query, and the threshold it was checked
+    /// against.
+    pub fn record_query(
+        &self,
+        pdb_name: &str,
+        (online, max): (u32, u32),
+        needed: f64,
+        threshold: &str,
+        source: &str,
+    ) {
+        let mut servers = self.servers.lock().unwrap();
+        let server = servers.entry(pdb_name.to_string()).or_default();
+        server.players_online = Some(online);
+        server.players_max = Some(max);
+        server.players_needed = Some(needed);
+        server.threshold = Some(threshold.to_string());
+        server.threshold_source = Some(source.to_string());
+        server.last_query = Some(Utc::now());
+    }
+
+    /// Record how long a query took.
+    pub fn record_latency(&self, pdb_name: &str, latency: Duration) {
+        let mut servers = self.servers.lock().unwrap();
+        let server = servers.entry(pdb_name.to_string()).or_default();
+        server.query_latency = Some(latency);
+        let seconds = latency.as_secs_f64();
+        for (count, bound) in server.query_buckets.iter_mut().zip(QUERY_DURATION_BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        server.query_count += 1;
+        server.query_seconds += seconds;
+    }
+
+    /// Record a successful patch of a PDB. A dry run only changes the state
+    /// the controller thinks the server is in, as nothing was patched.
+    pub fn record_patch(&self, pdb_name: &str, has_players: bool, dry_run: bool) {
+        let mut servers = self.servers.lock().unwrap();
+        let server = servers.entry(pdb_name.to_string()).or_default();
+        server.has_players = has_players;
+        if dry_run {
+            return;
+        }
+        server.last_patch = Some(Utc::now());
+        if has_players {
+            server.to_protected += 1;
+        } else {
+            server.to_unprotected += 1;
+        }
+    }
+
+    /// Record a change of a server's circuit breaker.
+    pub fn record_breaker(&self, pdb_name: &str, breaker: BreakerState) {
+        let mut servers = self.servers.lock().unwrap();
+        servers.entry(pdb_name.to_string()).or_default().breaker = breaker;
+    }
+
+    /// Record how long an update of every server took.
+    pub fn record_cycle(&self, duration: Duration) {
+        *self.last_cycle.lock().unwrap() = Some(duration);
+    }
+
+    /// Forget the servers that are no longer managed.
+    pub fn retain(&self, mut keep: impl FnMut(&str) -> bool) {
+        self.servers.lock().unwrap().retain(|name, _| keep(name));
+    }
+
+    /// The status of every server as JSON.
+    pub fn to_json(&self) -> serde_json::Value {
+        let servers = self.servers.lock().unwrap();
+        let servers: serde_json::Map<String, serde_json::Value> = servers
+            .iter()
+            .map(|(name, s)| {
+                let value = serde_json::json!({
+                    "players_online": s.players_online,
+                    "players_max": s.players_max,
+                    "players_needed": s.players_needed,
+                    "threshold": s.threshold,
+                    "threshold_source": s.threshold_source,
+                    "last_has_players": s.has_players,
+                    "last_query": s.last_query.map(|t| t.to_rfc3339()),
+                    "last_patch": s.last_patch.map(|t| t.to_rfc3339()),
+                    "query_latency_ms": s.query_latency.map(|d| d.as_secs_f64() * 1000.0),
+                    "circuit_breaker": s.breaker.name(),
+                });
+                (name.clone(), value)
+            })
+            .collect();
+        let last_cycle = *self.last_cycle.lock().unwrap();
+        serde_json::json!({
+            "servers": servers,
+            "last_cycle_ms": last_cycle.map(|d| d.as_secs_f64() * 1000.0),
+        })
+    }
+
+    /// The transitions and protection of every server in the Prometheus text
+    /// format.
+    pub fn to_metrics(&self) -> String {
+        let servers = self.servers.lock().unwrap();
+        let mut out = String::new();
+        out.push_str(
+            "# HELP minecraft_pdb_mgr_transitions_total Committed changes of a server's protection, by direction.\n\
+             # TYPE minecraft_pdb_mgr_transitions_total counter\n",
+        );
+        for (name, s) in servers.iter() {
+            let name = escape_label(name);
+            for (direction, count) in [
+                ("to_protected", s.to_protected),
+                ("to_unprotected", s.to_unprotected),
+            ] {
+                out.push_str(&format!(
+                    "minecraft_pdb_mgr_transitions_total{{pdb_name=\"{name}\",direction=\"{direction}\"}} {count}\n"
+                ));
+            }
+        }
+        out.push_str(
+            "# HELP minecraft_pdb_mgr_protected Whether the server is currently protected.\n\
+             # TYPE minecraft_pdb_mgr_protected gauge\n",
+        );
+        for (name, s) in servers.iter() {
+            out.push_str(&format!(
+                "minecraft_pdb_mgr_protected{{pdb_name=\"{}\"}} {}\n",
+                escape_label(name),
+                u8::from(s.has_players)
+            ));
+        }
+        out.push_str(
+            "# HELP minecraft_pdb_mgr_players_needed The online players the server was last checked against to be protected.\n\
+             # TYPE minecraft_pdb_mgr_players_needed gauge\n",
+        );
+        for (name, s) in servers.iter() {
+            // No protection rule applying needs infinitely many players.
+            let Some(needed) = s.players_needed else {
+                continue;
+            };
+            let needed = if needed.is_infinite() {
+                "+Inf".to_string()
+            } else {
+                needed.to_string()
+            };
+            out.push_str(&format!(
+                "minecraft_pdb_mgr_players_needed{{pdb_name=\"{}\"}} {needed}\n",
+                escape_label(name),
+            ));
+        }
+        out.push_str(
+            "# HELP minecraft_pdb_mgr_query_duration_seconds How long each query of the server took, whether or not it succeeded.\n\
+             # TYPE minecraft_pdb_mgr_query_duration_seconds histogram\n",
+        );
+        for (name, s) in servers.iter() {
+            let name = escape_label(name);
+            for (count, bound) in s.query_buckets.iter().zip(QUERY_DURATION_BUCKETS) {
+                out.push_str(&format!(
+                    "minecraft_pdb_mgr_query_duration_seconds_bucket{{pdb_name=\"{name}\",le=\"{bound}\"}} {count}\n"
+                ));
+            }
+            out.push_str(&format!(
+                "minecraft_pdb_mgr_query_duration_seconds_bucket{{pdb_name=\"{name}\",le=\"+Inf\"}} {}\n\
+                 minecraft_pdb_mgr_query_duration_seconds_sum{{pdb_name=\"{name}\"}} {}\n\
+                 minecraft_pdb_mgr_query_duration_seconds_count{{pdb_name=\"{name}\"}} {}\n",
+                s.query_count, s.query_seconds, s.query_count
+            ));
+        }
+        out.push_str(
+            "# HELP minecraft_pdb_mgr_circuit_breaker Whether the server's circuit breaker is in each state.\n\
+             # TYPE minecraft_pdb_mgr_circuit_breaker gauge\n",
+        );
+        for (name, s) in servers.iter() {
+            let name = escape_label(name);
+            for state in [
+                BreakerState::Closed,
+                BreakerState::Open,
+                BreakerState::HalfOpen,
+            ] {
+                out.push_str(&format!(
+                    "minecraft_pdb_mgr_circuit_breaker{{pdb_name=\"{name}\",state=\"{}\"}} {}\n",
+                    state.name(),
+                    u8::from(s.breaker == state)
+                ));
+            }
+        }
+        out
+    }
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the `/healthz`, `/readyz`, `/status`, and `/metrics` endpoints until
+/// the process exits.
+pub async fn serve(port: u16, health: Arc<Health>, status: Arc<Status>) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    tracing::info!("Serving health probes on port {port}.");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (health, status) = (health.clone(), status.clone());
+        tokio::spawn(async move {
+            let service = service_fn(|req| handle(req, health.clone(), status.clone()));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Health probe connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    health: Arc<Health>,
+    status: Arc<Status>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if req.uri().path() == "/status" {
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(status.to_json().to_string())))
+            .unwrap());
+    }
+    if req.uri().path() == "/metrics" {
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(status.to_metrics())))
+            .unwrap());
+    }
+    let (status, body) = match req.uri().path() {
+        "/healthz" => (StatusCode::OK, "ok"),
+        "/readyz" if health.is_ready() => (StatusCode::OK, "ok"),
+        "/readyz" => (StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+        _ => (StatusCode::NOT_FOUND, "not found"),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap())
+}
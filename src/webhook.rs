@@ -0,0 +1,66 @@
+//! Best-effort notifications of player state changes to a webhook, such as a
+//! Discord or Slack incoming webhook.
+
+use crate::config::{ServerConfig, Webhook};
+use anyhow::Result;
+use reqwest::header::CONTENT_TYPE;
+
+/// A committed change in whether a server has players.
+pub struct Transition<'a> {
+    pub server: &'a ServerConfig,
+    pub has_players: bool,
+    /// The online and max players, unless the change wasn't caused by them.
+    pub players: Option<(u32, u32)>,
+}
+
+/// POST a transition to the webhook, logging rather than returning failures.
+pub async fn notify(client: &reqwest::Client, webhook: &Webhook, transition: &Transition<'_>) {
+    if let Err(e) = send(client, webhook, transition).await {
+        tracing::warn!(
+            pdb_name = transition.server.key(),
+            "Failed to send webhook notification: {e}"
+        );
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    transition: &Transition<'_>,
+) -> Result<()> {
+    let server = transition.server;
+    let state = if transition.has_players {
+        "protected"
+    } else {
+        "unprotected"
+    };
+    let (players_online, players_max) = transition.players.unzip();
+    let body = match &webhook.template {
+        Some(template) => {
+            let count = |n: Option<u32>| n.map_or("unknown".to_string(), |n| n.to_string());
+            template
+                .replace("{pdb_name}", &server.key())
+                .replace("{server_host}", &server.server_host)
+                .replace("{state}", state)
+                .replace("{players_online}", &count(players_online))
+                .replace("{players_max}", &count(players_max))
+        }
+        None => serde_json::json!({
+            "pdb_name": server.key(),
+            "server_host": server.server_host,
+            "state": state,
+            "players_online": players_online,
+            "players_max": players_max,
+        })
+        .to_string(),
+    };
+    client
+        .post(&webhook.url.0)
+        .timeout(webhook.timeout)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}